@@ -0,0 +1,397 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::{object_format, Arch, NasmVersion, Target};
+
+/// The assembler used to build sources.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Assembler {
+    /// The Netwide Assembler, <https://www.nasm.us/>
+    #[default]
+    Nasm,
+    /// The Yasm Modular Assembler, <https://yasm.tortall.net/>
+    Yasm,
+}
+
+/// How a class of warnings is treated, see [`Build::warning`](crate::Build::warning).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WarningLevel {
+    /// Report warnings of this class.
+    Enable,
+    /// Don't report warnings of this class.
+    Disable,
+    /// Restore the assembler's default for this class.
+    Default,
+    /// Report warnings of this class as errors.
+    Error,
+    /// Report warnings of this class as warnings, even with
+    /// [`Build::warnings_into_errors`](crate::Build::warnings_into_errors).
+    NoError,
+}
+
+/// How an assembler reports the files a source depends on.
+pub(crate) enum DependencyArgs {
+    /// Extra arguments that write a Makefile-style dependency file while assembling.
+    Inline(Vec<OsString>),
+    /// Arguments for a separate run that prints dependencies to stdout.
+    Scan(Vec<OsString>),
+}
+
+impl Assembler {
+    /// Name of the executable, also used in diagnostics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Assembler::Nasm => "nasm",
+            Assembler::Yasm => "yasm",
+        }
+    }
+
+    /// Base name of the environment variables used to override the executable.
+    pub(crate) fn env_var(&self) -> &'static str {
+        match self {
+            Assembler::Nasm => "NASM",
+            Assembler::Yasm => "YASM",
+        }
+    }
+
+    pub(crate) fn version_args(&self) -> &'static [&'static str] {
+        match self {
+            Assembler::Nasm => &["-v"],
+            Assembler::Yasm => &["--version"],
+        }
+    }
+
+    /// Parses the output of the version query, e.g. `NASM version 2.14.02` or `yasm 1.3.0`.
+    pub fn parse_version(&self, version: &str) -> Result<NasmVersion, String> {
+        match self {
+            Assembler::Nasm => crate::parse_nasm_version(version),
+            Assembler::Yasm => parse_yasm_version(version),
+        }
+    }
+
+    /// Output format and debug info arguments for `target`.
+    pub(crate) fn target_args(&self, target: &Target, debug: bool) -> Vec<&'static str> {
+        let (arch_flag, debug_flag) = object_format(target);
+        let mut args = Vec::new();
+        match self {
+            Assembler::Nasm => {
+                if !arch_flag.is_empty() {
+                    args.push(arch_flag);
+                }
+                if debug {
+                    args.push(debug_flag);
+                }
+            }
+            Assembler::Yasm => {
+                let format = arch_flag.trim_start_matches("-f");
+                if !format.is_empty() {
+                    args.extend(["-f", format]);
+                    if debug {
+                        let debug_format = if format.starts_with("win") {
+                            "cv8"
+                        } else {
+                            "dwarf2"
+                        };
+                        args.extend(["-g", debug_format]);
+                    }
+                }
+            }
+        }
+        args
+    }
+
+    /// Arguments making memory operands RIP-relative by default.
+    pub(crate) fn default_rel_args(&self, target: &Target) -> &'static [&'static str] {
+        match (self, &target.arch) {
+            // YASM can't prepend lines to the source.
+            (Assembler::Nasm, Arch::X86_64) => &["--before", "default rel"],
+            _ => &[],
+        }
+    }
+
+    pub(crate) fn warning_args(&self, class: &str, level: WarningLevel) -> Option<String> {
+        match (self, level) {
+            (Assembler::Nasm, WarningLevel::Enable) => Some(format!("-w+{}", class)),
+            (Assembler::Nasm, WarningLevel::Disable) => Some(format!("-w-{}", class)),
+            (Assembler::Nasm, WarningLevel::Default) => Some(format!("-w*{}", class)),
+            (Assembler::Nasm, WarningLevel::Error) => Some(format!("-w+error={}", class)),
+            (Assembler::Nasm, WarningLevel::NoError) => Some(format!("-w-error={}", class)),
+            (Assembler::Yasm, WarningLevel::Enable) | (Assembler::Yasm, WarningLevel::Error) => {
+                Some(format!("-W{}", class))
+            }
+            (Assembler::Yasm, WarningLevel::Disable) => Some(format!("-Wno-{}", class)),
+            (Assembler::Yasm, WarningLevel::Default) | (Assembler::Yasm, WarningLevel::NoError) => {
+                None
+            }
+        }
+    }
+
+    /// Maps a Cargo `opt-level` to an optimization flag. YASM always
+    /// optimizes, so it has none.
+    pub(crate) fn opt_level_arg(&self, opt_level: &str) -> Option<&'static str> {
+        match (self, opt_level) {
+            (Assembler::Yasm, _) => None,
+            (Assembler::Nasm, "0") => Some("-O0"),
+            (Assembler::Nasm, "1") => Some("-O1"),
+            (Assembler::Nasm, "2") | (Assembler::Nasm, "3") => Some("-Ox"),
+            (Assembler::Nasm, "s") | (Assembler::Nasm, "z") => Some("-Ox"),
+            (Assembler::Nasm, _) => None,
+        }
+    }
+
+    pub(crate) fn warnings_into_errors_flag(&self) -> &'static str {
+        match self {
+            Assembler::Nasm => "-w+error",
+            Assembler::Yasm => "-Werror",
+        }
+    }
+
+    /// Whether arguments can be passed in a `-@` response file.
+    pub(crate) fn supports_response_files(&self) -> bool {
+        match self {
+            Assembler::Nasm => true,
+            Assembler::Yasm => false,
+        }
+    }
+
+    /// Flag which stops after preprocessing, writing the result to the output file.
+    pub(crate) fn preprocess_flag(&self) -> &'static str {
+        match self {
+            Assembler::Nasm => "-E",
+            Assembler::Yasm => "-e",
+        }
+    }
+
+    pub(crate) fn dependency_args(&self, depfile: &Path) -> DependencyArgs {
+        match self {
+            Assembler::Nasm => DependencyArgs::Inline(vec!["-MD".into(), depfile.into()]),
+            Assembler::Yasm => DependencyArgs::Scan(vec!["-M".into()]),
+        }
+    }
+
+    fn severity(&self, word: &str) -> Option<Severity> {
+        match (self, word) {
+            (Assembler::Nasm, "fatal") | (Assembler::Nasm, "panic") => Some(Severity::Fatal),
+            (Assembler::Nasm, "error") | (Assembler::Nasm, "nonfatal") => Some(Severity::Error),
+            (Assembler::Nasm, "note") | (Assembler::Nasm, "info") => Some(Severity::Note),
+            (Assembler::Yasm, "FATAL") => Some(Severity::Fatal),
+            (Assembler::Yasm, "error") => Some(Severity::Error),
+            (_, "warning") => Some(Severity::Warning),
+            _ => None,
+        }
+    }
+
+    /// Parses the assembler's stderr into diagnostics.
+    ///
+    /// Lines which don't look like `file:line: severity: message` or
+    /// `nasm: severity: message` are ignored.
+    pub fn parse_diagnostics(&self, output: &str) -> Vec<Diagnostic> {
+        output
+            .lines()
+            .filter_map(|line| self.parse_diagnostic(line))
+            .collect()
+    }
+
+    fn parse_diagnostic(&self, line: &str) -> Option<Diagnostic> {
+        // Search for the severity rather than splitting on ':', since Windows
+        // paths contain colons too.
+        let mut search_from = 0;
+        while let Some(offset) = line[search_from..].find(": ") {
+            let start = search_from + offset;
+            let rest = &line[start + 2..];
+            if let Some((word, message)) = rest.split_once(':') {
+                if let Some(severity) = self.severity(word) {
+                    let (file, line_no) = self.parse_location(&line[..start]);
+                    return Some(Diagnostic {
+                        file,
+                        line: line_no,
+                        severity,
+                        message: message.trim().to_owned(),
+                    });
+                }
+            }
+            search_from = start + 2;
+        }
+        None
+    }
+
+    fn parse_location(&self, location: &str) -> (Option<PathBuf>, Option<u32>) {
+        if location == self.name() {
+            return (None, None);
+        }
+        match location.rsplit_once(':') {
+            Some((file, line)) => match line.parse() {
+                Ok(line) => (Some(PathBuf::from(file)), Some(line)),
+                Err(_) => (Some(PathBuf::from(location)), None),
+            },
+            None => (Some(PathBuf::from(location)), None),
+        }
+    }
+}
+
+impl fmt::Display for Assembler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How serious a [`Diagnostic`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Note,
+    Warning,
+    Error,
+    Fatal,
+}
+
+/// A single message reported by the assembler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub file: Option<PathBuf>,
+    pub line: Option<u32>,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Note => "note",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+            Severity::Fatal => "fatal",
+        })
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{}:{}: ", file.display(), line)?,
+            (Some(file), None) => write!(f, "{}: ", file.display())?,
+            _ => {}
+        }
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+fn parse_yasm_version(version: &str) -> Result<NasmVersion, String> {
+    version
+        .split_whitespace()
+        .nth(1)
+        .ok_or_else(|| format!("Invalid yasm version '{}'", version))?
+        .parse()
+        .map(NasmVersion::unpadded)
+}
+
+/// Parses a Makefile-style dependency file, as written by `nasm -MD` or
+/// `yasm -M`, returning the prerequisites of every rule.
+pub(crate) fn parse_dependencies(deps: &str) -> Vec<PathBuf> {
+    let joined = deps.replace("\\\r\n", " ").replace("\\\n", " ");
+    let mut files = Vec::new();
+    for rule in joined.lines() {
+        // Rule targets may contain drive letters, so split on ": " rather than ':'.
+        let prerequisites = match rule.split_once(": ") {
+            Some((_, prerequisites)) => prerequisites,
+            None => continue,
+        };
+
+        let mut current = String::new();
+        let mut chars = prerequisites.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.peek() == Some(&' ') => {
+                    current.push(' ');
+                    chars.next();
+                }
+                c if c.is_whitespace() => {
+                    if !current.is_empty() {
+                        files.push(PathBuf::from(std::mem::take(&mut current)));
+                    }
+                }
+                c => current.push(c),
+            }
+        }
+        if !current.is_empty() {
+            files.push(PathBuf::from(current));
+        }
+    }
+    files
+}
+
+#[test]
+fn test_yasm_target_args() {
+    assert_eq!(
+        Assembler::Yasm.target_args(&Target::parse("x86_64-pc-windows-msvc"), true),
+        &["-f", "win64", "-g", "cv8"]
+    );
+    assert_eq!(
+        Assembler::Yasm.target_args(&Target::parse("x86_64-unknown-linux-gnu"), true),
+        &["-f", "elf64", "-g", "dwarf2"]
+    );
+    assert_eq!(
+        Assembler::Nasm.target_args(&Target::parse("x86_64-unknown-linux-gnu"), true),
+        &["-felf64", "-gdwarf"]
+    );
+}
+
+#[test]
+fn test_parse_yasm_version() {
+    let ver_str = "yasm 1.3.0\nCompiled on Jun 18 2019.";
+    assert_eq!(
+        NasmVersion::new(1, 3, 0),
+        Assembler::Yasm.parse_version(ver_str).unwrap()
+    );
+    assert_eq!(
+        Assembler::Yasm.parse_version(ver_str).unwrap().to_string(),
+        "1.3.0"
+    );
+    let ver_str = "yasm 1.2.0.86-g9def";
+    let ver = Assembler::Yasm.parse_version(ver_str).unwrap();
+    assert_eq!((1, 2, 0), (ver.major, ver.minor, ver.micro));
+    assert_eq!(ver.to_string(), "1.2.0.86");
+    assert!(Assembler::Yasm.parse_version("yasm").is_err());
+}
+
+#[test]
+fn test_parse_diagnostics() {
+    let stderr = "foo.asm:3: error: parser: instruction expected\n\
+                  C:\\src\\bar.asm:10: warning: label alone on a line [-w+label-orphan]\n\
+                  nasm: fatal: unable to open input file `baz.asm'\n\
+                  unrelated output";
+    let diags = Assembler::Nasm.parse_diagnostics(stderr);
+    assert_eq!(diags.len(), 3);
+    assert_eq!(diags[0].file, Some(PathBuf::from("foo.asm")));
+    assert_eq!(diags[0].line, Some(3));
+    assert_eq!(diags[0].severity, Severity::Error);
+    assert_eq!(diags[0].message, "parser: instruction expected");
+    assert_eq!(diags[1].file, Some(PathBuf::from("C:\\src\\bar.asm")));
+    assert_eq!(diags[1].severity, Severity::Warning);
+    assert_eq!(diags[2].file, None);
+    assert_eq!(diags[2].severity, Severity::Fatal);
+
+    let stderr = "yasm: FATAL: unable to open input file `baz.asm'\n\
+                  foo.asm:7: error: undefined symbol `bar' (first use)";
+    let diags = Assembler::Yasm.parse_diagnostics(stderr);
+    assert_eq!(diags[0].severity, Severity::Fatal);
+    assert_eq!(diags[1].line, Some(7));
+    assert_eq!(
+        diags[1].to_string(),
+        "foo.asm:7: error: undefined symbol `bar' (first use)"
+    );
+}
+
+#[test]
+fn test_parse_dependencies() {
+    let deps = "/out/foo.o: src/foo.asm src/inc/macros.inc \\\n  src/dir\\ with\\ space/x.inc\n\
+                src/inc/macros.inc:\n";
+    assert_eq!(
+        parse_dependencies(deps),
+        &[
+            PathBuf::from("src/foo.asm"),
+            PathBuf::from("src/inc/macros.inc"),
+            PathBuf::from("src/dir with space/x.inc"),
+        ]
+    );
+}
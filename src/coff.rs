@@ -0,0 +1,221 @@
+//! Just enough of a COFF reader to find exported functions without unwind
+//! information in 64-bit Windows objects.
+
+use std::collections::HashSet;
+use std::convert::TryInto;
+
+const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+
+const IMAGE_SCN_CNT_CODE: u32 = 0x20;
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+
+const IMAGE_SYM_CLASS_EXTERNAL: u8 = 2;
+
+const IMAGE_REL_AMD64_ADDR32NB: u16 = 3;
+
+/// Size of a `RUNTIME_FUNCTION` entry in `.pdata`.
+const RUNTIME_FUNCTION_SIZE: u32 = 12;
+
+struct Section {
+    name: Vec<u8>,
+    characteristics: u32,
+    data: usize,
+    relocations: usize,
+    relocation_count: usize,
+}
+
+struct Symbol {
+    name: String,
+    value: u32,
+    section: i16,
+    class: u8,
+}
+
+/// Finds the exported functions of a 64-bit Windows object that have no
+/// `.pdata` entry.
+///
+/// Objects of other formats or architectures have none.
+pub(crate) fn functions_without_unwind_info(data: &[u8]) -> Result<Vec<String>, String> {
+    let coff = Coff { data };
+    if data.len() < 20 || coff.u16(0)? != IMAGE_FILE_MACHINE_AMD64 {
+        return Ok(Vec::new());
+    }
+    let sections = coff.sections()?;
+    let symbols = coff.symbols()?;
+
+    // The function each `.pdata` entry begins at, as (section number, offset).
+    let mut unwound = HashSet::new();
+    for section in sections.iter().filter(|s| s.name == b".pdata") {
+        for i in 0..section.relocation_count {
+            let at = section.relocations + i * 10;
+            let offset = coff.u32(at)?;
+            let symbol = coff.u32(at + 4)? as usize;
+            let kind = coff.u16(at + 8)?;
+            if kind != IMAGE_REL_AMD64_ADDR32NB || offset % RUNTIME_FUNCTION_SIZE != 0 {
+                continue;
+            }
+            let symbol = symbols
+                .get(symbol)
+                .and_then(Option::as_ref)
+                .ok_or_else(|| format!("invalid symbol index {}", symbol))?;
+            let addend = coff.u32(section.data + offset as usize)?;
+            unwound.insert((symbol.section, symbol.value.wrapping_add(addend)));
+        }
+    }
+
+    let is_code = |number: i16| {
+        number > 0
+            && sections.get(number as usize - 1).is_some_and(|s| {
+                s.characteristics & (IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE) != 0
+            })
+    };
+    Ok(symbols
+        .iter()
+        .flatten()
+        .filter(|s| s.class == IMAGE_SYM_CLASS_EXTERNAL && is_code(s.section))
+        .filter(|s| !unwound.contains(&(s.section, s.value)))
+        .map(|s| s.name.clone())
+        .collect())
+}
+
+struct Coff<'a> {
+    data: &'a [u8],
+}
+
+impl Coff<'_> {
+    fn sections(&self) -> Result<Vec<Section>, String> {
+        let count = usize::from(self.u16(2)?);
+        let first = 20 + usize::from(self.u16(16)?);
+        (0..count)
+            .map(|i| {
+                let at = first + i * 40;
+                let name = self.bytes::<8>(at)?;
+                let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                Ok(Section {
+                    name: name[..len].to_vec(),
+                    characteristics: self.u32(at + 36)?,
+                    data: self.u32(at + 20)? as usize,
+                    relocations: self.u32(at + 24)? as usize,
+                    relocation_count: usize::from(self.u16(at + 32)?),
+                })
+            })
+            .collect()
+    }
+
+    /// The symbol table, with `None` in the slots taken by auxiliary records
+    /// so that relocations can index it directly.
+    fn symbols(&self) -> Result<Vec<Option<Symbol>>, String> {
+        let table = self.u32(8)? as usize;
+        let count = self.u32(12)? as usize;
+        let strings = table + count * 18;
+
+        let mut symbols = Vec::with_capacity(count);
+        while symbols.len() < count {
+            let at = table + symbols.len() * 18;
+            let short_name = self.bytes::<8>(at)?;
+            let name = if short_name[..4] == [0; 4] {
+                self.string(strings + self.u32(at + 4)? as usize)?
+            } else {
+                let len = short_name.iter().position(|&b| b == 0).unwrap_or(8);
+                String::from_utf8_lossy(&short_name[..len]).into_owned()
+            };
+            symbols.push(Some(Symbol {
+                name,
+                value: self.u32(at + 8)?,
+                section: self.u16(at + 12)? as i16,
+                class: self.bytes::<1>(at + 16)?[0],
+            }));
+            let aux = self.bytes::<1>(at + 17)?[0];
+            symbols.extend((0..aux).map(|_| None));
+        }
+        Ok(symbols)
+    }
+
+    fn string(&self, at: usize) -> Result<String, String> {
+        let bytes = self.data.get(at..).ok_or("truncated string table")?;
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
+
+    fn bytes<const N: usize>(&self, at: usize) -> Result<[u8; N], String> {
+        self.data
+            .get(at..at + N)
+            .and_then(|b| b.try_into().ok())
+            .ok_or_else(|| "truncated COFF object".to_owned())
+    }
+
+    fn u16(&self, at: usize) -> Result<u16, String> {
+        self.bytes(at).map(u16::from_le_bytes)
+    }
+
+    fn u32(&self, at: usize) -> Result<u32, String> {
+        self.bytes(at).map(u32::from_le_bytes)
+    }
+}
+
+/// Builds an object with the exported functions `covered` at offset 0 and
+/// `missing` at offset 16 of `.text`, and a `.pdata` entry for `covered`
+/// relocated against the `.text` section symbol.
+#[cfg(test)]
+fn test_object() -> Vec<u8> {
+    let mut data = vec![0u8; 20 + 2 * 40];
+    let text = data.len();
+    data.extend([0xc3; 32]);
+    let pdata = data.len();
+    data.extend(0u32.to_le_bytes());
+    data.extend(1u32.to_le_bytes());
+    data.extend(0u32.to_le_bytes());
+    let relocations = data.len();
+    for (offset, symbol) in [(0u32, 0u32), (4, 0)] {
+        data.extend(offset.to_le_bytes());
+        data.extend(symbol.to_le_bytes());
+        data.extend(IMAGE_REL_AMD64_ADDR32NB.to_le_bytes());
+    }
+
+    let symbols = data.len();
+    let mut symbol = |name: &[u8], value: u32, section: u16, class: u8, aux: u8| {
+        let mut entry = [0u8; 18];
+        entry[..name.len()].copy_from_slice(name);
+        entry[8..12].copy_from_slice(&value.to_le_bytes());
+        entry[12..14].copy_from_slice(&section.to_le_bytes());
+        entry[16] = class;
+        entry[17] = aux;
+        data.extend(entry);
+    };
+    // `.text` section symbol with one auxiliary record.
+    symbol(b".text", 0, 1, 3, 1);
+    symbol(b"", 0, 0, 0, 0);
+    symbol(b"covered", 0, 1, IMAGE_SYM_CLASS_EXTERNAL, 0);
+    symbol(b"missing", 16, 1, IMAGE_SYM_CLASS_EXTERNAL, 0);
+    data.extend(4u32.to_le_bytes());
+
+    data[0..2].copy_from_slice(&IMAGE_FILE_MACHINE_AMD64.to_le_bytes());
+    data[2..4].copy_from_slice(&2u16.to_le_bytes());
+    data[8..12].copy_from_slice(&(symbols as u32).to_le_bytes());
+    data[12..16].copy_from_slice(&4u32.to_le_bytes());
+    let headers = [
+        (b".text\0\0\0", text, 32, 0, 0, IMAGE_SCN_CNT_CODE),
+        (b".pdata\0\0", pdata, 12, relocations, 2, 0x40),
+    ];
+    for (i, (name, raw, size, relocs, count, flags)) in headers.iter().enumerate() {
+        let at = 20 + i * 40;
+        data[at..at + 8].copy_from_slice(&name[..]);
+        data[at + 16..at + 20].copy_from_slice(&(*size as u32).to_le_bytes());
+        data[at + 20..at + 24].copy_from_slice(&(*raw as u32).to_le_bytes());
+        data[at + 24..at + 28].copy_from_slice(&(*relocs as u32).to_le_bytes());
+        data[at + 32..at + 34].copy_from_slice(&(*count as u16).to_le_bytes());
+        data[at + 36..at + 40].copy_from_slice(&flags.to_le_bytes());
+    }
+    data
+}
+
+#[test]
+fn test_functions_without_unwind_info() {
+    assert_eq!(
+        functions_without_unwind_info(&test_object()).unwrap(),
+        &["missing"]
+    );
+    assert!(functions_without_unwind_info(b"\x7fELF")
+        .unwrap()
+        .is_empty());
+}
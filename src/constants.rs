@@ -0,0 +1,182 @@
+use std::env;
+use std::fmt::{self, Write as _};
+use std::path::Path;
+
+/// An include file of constants generated by the build script, so assembly
+/// doesn't have to hard-code values that are known to Rust.
+///
+/// Pass it to [`Build::constants`](crate::Build::constants) to have it written
+/// to `OUT_DIR` and added to the include path.
+///
+/// # Example
+///
+/// ```no_run
+/// #[repr(C)]
+/// struct Context {
+///     rsp: u64,
+///     rip: u64,
+/// }
+///
+/// let mut constants = nasm_rs::Constants::new("context.inc");
+/// constants.define("STACK_SIZE", 0x4000);
+/// // Only the host's layout is known to the build script; see `struct_layout!`.
+/// nasm_rs::struct_layout!(constants, Context { rsp, rip });
+///
+/// nasm_rs::Build::new()
+///     .constants(&constants)
+///     .file("src/switch.asm")
+///     .compile("switch")
+///     .unwrap();
+/// ```
+///
+/// `src/switch.asm` can then `%include "context.inc"` and use `STACK_SIZE`,
+/// `Context_size`, `Context_rsp` and `Context_rip`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Constants {
+    file_name: String,
+    lines: Vec<String>,
+}
+
+impl Constants {
+    /// Creates an empty set of constants, written to `file_name`.
+    pub fn new(file_name: &str) -> Self {
+        Self {
+            file_name: file_name.to_owned(),
+            lines: Vec::new(),
+        }
+    }
+
+    /// Adds `%define name value`, usable in preprocessor conditions.
+    pub fn define<V: fmt::Display>(&mut self, name: &str, value: V) -> &mut Self {
+        self.lines.push(format!("%define {} {}", name, value));
+        self
+    }
+
+    /// Adds `name equ value`, a constant symbol.
+    pub fn equ<V: fmt::Display>(&mut self, name: &str, value: V) -> &mut Self {
+        self.lines.push(format!("{} equ {}", name, value));
+        self
+    }
+
+    /// Adds `<name>_size` and `<name>_<field>` constants for the size of a
+    /// struct and the offsets of its fields.
+    ///
+    /// [`struct_layout!`](crate::struct_layout) fills these in from a
+    /// `#[repr(C)]` type.
+    pub fn struct_layout(
+        &mut self,
+        name: &str,
+        size: usize,
+        fields: &[(&str, usize)],
+    ) -> &mut Self {
+        self.equ(&format!("{}_size", name), size);
+        for (field, offset) in fields {
+            self.equ(&format!("{}_{}", name, field), offset);
+        }
+        self
+    }
+
+    /// [`Constants::struct_layout`] for a layout computed by the build script,
+    /// warning if it may differ on the target. Used by
+    /// [`struct_layout!`](crate::struct_layout).
+    #[doc(hidden)]
+    pub fn host_struct_layout(
+        &mut self,
+        name: &str,
+        size: usize,
+        fields: &[(&str, usize)],
+    ) -> &mut Self {
+        if let Some(target) = layout_mismatch() {
+            println!(
+                "cargo:warning=The layout of `{}` was computed for the host ({}-{}), \
+                 not the target ({}); its offsets may be wrong.",
+                name,
+                env::consts::ARCH,
+                env::consts::OS,
+                target
+            );
+        }
+        self.struct_layout(name, size, fields)
+    }
+
+    /// Name of the generated file, as used in `%include`.
+    pub fn file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    /// Contents of the generated file.
+    pub fn contents(&self) -> String {
+        let mut contents = String::from("; Generated by nasm-rs, do not edit.\n");
+        for line in &self.lines {
+            let _ = writeln!(contents, "{}", line);
+        }
+        contents
+    }
+
+    /// Writes the file into `dir`.
+    pub fn write_to<P: AsRef<Path>>(&self, dir: P) -> Result<(), String> {
+        let path = dir.as_ref().join(&self.file_name);
+        std::fs::create_dir_all(dir.as_ref())
+            .and_then(|_| std::fs::write(&path, self.contents()))
+            .map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+    }
+}
+
+/// Describes the target if its architecture, OS or pointer width differ from
+/// the host's, which the build script is compiled for.
+fn layout_mismatch() -> Option<String> {
+    let cfg = |key: &str| env::var(key).ok();
+    let arch = cfg("CARGO_CFG_TARGET_ARCH")?;
+    let os = cfg("CARGO_CFG_TARGET_OS")?;
+    let pointer_width = cfg("CARGO_CFG_TARGET_POINTER_WIDTH")?;
+    let host_pointer_width = (std::mem::size_of::<usize>() * 8).to_string();
+    if arch == env::consts::ARCH && os == env::consts::OS && pointer_width == host_pointer_width {
+        None
+    } else {
+        Some(format!("{}-{}", arch, os))
+    }
+}
+
+/// Adds the size and field offsets of a struct to [`Constants`].
+///
+/// `struct_layout!(constants, Context { rsp, rip })` is shorthand for
+/// [`Constants::struct_layout`] with `"Context"`, `size_of::<Context>()` and
+/// `offset_of!(Context, rsp)` and `offset_of!(Context, rip)`.
+///
+/// The build script is compiled for the host, so the layout is the host's.
+/// When cross-compiling to a target with a different architecture, OS or
+/// pointer width a warning is printed, since fields like `u64` or `usize` may
+/// be aligned differently there; pass the target's values to
+/// [`Constants::struct_layout`] instead.
+#[macro_export]
+macro_rules! struct_layout {
+    ($constants:expr, $ty:ident { $($field:ident),* $(,)? }) => {
+        $constants.host_struct_layout(
+            stringify!($ty),
+            ::core::mem::size_of::<$ty>(),
+            &[$((stringify!($field), ::core::mem::offset_of!($ty, $field))),*],
+        )
+    };
+}
+
+#[test]
+fn test_constants() {
+    #[repr(C)]
+    struct Context {
+        flags: u64,
+        rsp: u64,
+    }
+
+    let mut constants = Constants::new("context.inc");
+    constants.define("HAVE_AVX2", 1).equ("STACK_SIZE", 0x4000);
+    struct_layout!(constants, Context { flags, rsp });
+    assert_eq!(
+        constants.contents(),
+        "; Generated by nasm-rs, do not edit.\n\
+         %define HAVE_AVX2 1\n\
+         STACK_SIZE equ 16384\n\
+         Context_size equ 16\n\
+         Context_flags equ 0\n\
+         Context_rsp equ 8\n"
+    );
+}
@@ -0,0 +1,317 @@
+//! Just enough of an ELF reader to find relocations which can't be used in
+//! position independent code.
+
+use std::convert::TryInto;
+use std::fmt;
+
+const EM_386: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_RELA: u32 = 4;
+const SHT_REL: u32 = 9;
+
+const SHF_EXECINSTR: u64 = 0x4;
+
+const STT_SECTION: u8 = 3;
+
+/// A relocation that needs the object to be loaded at a fixed address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AbsoluteRelocation {
+    pub(crate) kind: &'static str,
+    pub(crate) symbol: String,
+    pub(crate) section: String,
+    pub(crate) offset: u64,
+}
+
+impl fmt::Display for AbsoluteRelocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} against `{}` in {}+{:#x}",
+            self.kind, self.symbol, self.section, self.offset
+        )
+    }
+}
+
+struct Section {
+    name: String,
+    kind: u32,
+    flags: u64,
+    offset: usize,
+    size: usize,
+    link: usize,
+    info: usize,
+}
+
+struct Elf<'a> {
+    data: &'a [u8],
+    is_64: bool,
+    machine: u16,
+    sections: Vec<Section>,
+}
+
+/// Finds the relocations in an x86 ELF object which would make the linker
+/// reject it for PIE or shared objects, or need text relocations.
+///
+/// Objects of other formats or architectures have none.
+pub(crate) fn absolute_relocations(data: &[u8]) -> Result<Vec<AbsoluteRelocation>, String> {
+    let elf = match Elf::parse(data)? {
+        Some(elf) => elf,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut found = Vec::new();
+    for section in &elf.sections {
+        if section.kind != SHT_RELA && section.kind != SHT_REL {
+            continue;
+        }
+        let target = elf.section(section.info)?;
+        let symtab = elf.section(section.link)?;
+        let executable = target.flags & SHF_EXECINSTR != 0;
+
+        let entry_size = match (elf.is_64, section.kind) {
+            (true, SHT_RELA) => 24,
+            (true, _) => 16,
+            (false, SHT_RELA) => 12,
+            (false, _) => 8,
+        };
+        for i in 0..section.size / entry_size {
+            let at = section.offset + i * entry_size;
+            let (offset, sym, kind) = if elf.is_64 {
+                let info = elf.u64(at + 8)?;
+                (elf.u64(at)?, (info >> 32) as usize, info as u32)
+            } else {
+                let info = elf.u32(at + 4)?;
+                (u64::from(elf.u32(at)?), (info >> 8) as usize, info & 0xff)
+            };
+            if let Some(kind) = elf.absolute_kind(kind, executable) {
+                found.push(AbsoluteRelocation {
+                    kind,
+                    symbol: elf.symbol_name(symtab, sym)?,
+                    section: target.name.clone(),
+                    offset,
+                });
+            }
+        }
+    }
+    Ok(found)
+}
+
+impl<'a> Elf<'a> {
+    fn parse(data: &'a [u8]) -> Result<Option<Self>, String> {
+        // Only little-endian objects can be x86.
+        if data.len() < 6 || &data[..4] != b"\x7fELF" || data[5] != 1 {
+            return Ok(None);
+        }
+        let mut elf = Elf {
+            data,
+            is_64: data[4] == 2,
+            machine: 0,
+            sections: Vec::new(),
+        };
+        elf.machine = elf.u16(18)?;
+        if elf.machine != EM_386 && elf.machine != EM_X86_64 {
+            return Ok(None);
+        }
+
+        let (shoff, shentsize, shnum, shstrndx) = if elf.is_64 {
+            (
+                elf.u64(40)? as usize,
+                elf.u16(58)?,
+                elf.u16(60)?,
+                elf.u16(62)?,
+            )
+        } else {
+            (
+                elf.u32(32)? as usize,
+                elf.u16(46)?,
+                elf.u16(48)?,
+                elf.u16(50)?,
+            )
+        };
+
+        let mut names = Vec::new();
+        for i in 0..usize::from(shnum) {
+            let at = shoff + i * usize::from(shentsize);
+            let section = if elf.is_64 {
+                Section {
+                    name: String::new(),
+                    kind: elf.u32(at + 4)?,
+                    flags: elf.u64(at + 8)?,
+                    offset: elf.u64(at + 24)? as usize,
+                    size: elf.u64(at + 32)? as usize,
+                    link: elf.u32(at + 40)? as usize,
+                    info: elf.u32(at + 44)? as usize,
+                }
+            } else {
+                Section {
+                    name: String::new(),
+                    kind: elf.u32(at + 4)?,
+                    flags: u64::from(elf.u32(at + 8)?),
+                    offset: elf.u32(at + 16)? as usize,
+                    size: elf.u32(at + 20)? as usize,
+                    link: elf.u32(at + 24)? as usize,
+                    info: elf.u32(at + 28)? as usize,
+                }
+            };
+            names.push(elf.u32(at)? as usize);
+            elf.sections.push(section);
+        }
+
+        let shstrtab = elf.section(usize::from(shstrndx))?.offset;
+        for (i, name) in names.into_iter().enumerate() {
+            elf.sections[i].name = elf.string(shstrtab + name)?;
+        }
+        Ok(Some(elf))
+    }
+
+    fn absolute_kind(&self, kind: u32, executable: bool) -> Option<&'static str> {
+        match (self.machine, kind) {
+            // Only 64-bit x86 objects can't hold 32-bit absolute addresses at all.
+            (EM_X86_64, 10) if self.is_64 || executable => Some("R_X86_64_32"),
+            (EM_X86_64, 11) if self.is_64 || executable => Some("R_X86_64_32S"),
+            (EM_X86_64, 1) if executable => Some("R_X86_64_64"),
+            (EM_386, 1) if executable => Some("R_386_32"),
+            _ => None,
+        }
+    }
+
+    fn symbol_name(&self, symtab: &Section, index: usize) -> Result<String, String> {
+        if symtab.kind != SHT_SYMTAB {
+            return Ok(String::new());
+        }
+        let at = symtab.offset + index * if self.is_64 { 24 } else { 16 };
+        let (name, info, shndx) = if self.is_64 {
+            (self.u32(at)?, self.byte(at + 4)?, self.u16(at + 6)?)
+        } else {
+            (self.u32(at)?, self.byte(at + 12)?, self.u16(at + 14)?)
+        };
+        if info & 0xf == STT_SECTION {
+            return Ok(self.section(usize::from(shndx))?.name.clone());
+        }
+        let strtab = self.section(symtab.link)?.offset;
+        self.string(strtab + name as usize)
+    }
+
+    fn section(&self, index: usize) -> Result<&Section, String> {
+        self.sections
+            .get(index)
+            .ok_or_else(|| format!("invalid section index {}", index))
+    }
+
+    fn string(&self, at: usize) -> Result<String, String> {
+        let bytes = self.data.get(at..).ok_or("truncated string table")?;
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
+
+    fn bytes<const N: usize>(&self, at: usize) -> Result<[u8; N], String> {
+        self.data
+            .get(at..at + N)
+            .and_then(|b| b.try_into().ok())
+            .ok_or_else(|| "truncated ELF object".to_owned())
+    }
+
+    fn byte(&self, at: usize) -> Result<u8, String> {
+        Ok(self.bytes::<1>(at)?[0])
+    }
+
+    fn u16(&self, at: usize) -> Result<u16, String> {
+        self.bytes(at).map(u16::from_le_bytes)
+    }
+
+    fn u32(&self, at: usize) -> Result<u32, String> {
+        self.bytes(at).map(u32::from_le_bytes)
+    }
+
+    fn u64(&self, at: usize) -> Result<u64, String> {
+        self.bytes(at).map(u64::from_le_bytes)
+    }
+}
+
+/// Builds a 64-bit x86 object with a `.text` section relocated by `relocs`,
+/// which are `(offset, symbol index, type)` against the symbols `table` (1)
+/// and `.text` (2).
+#[cfg(test)]
+fn test_object(relocs: &[(u64, u64, u64)]) -> Vec<u8> {
+    let shstrtab = b"\0.text\0.rela.text\0.symtab\0.strtab\0.shstrtab\0";
+    let strtab = b"\0table\0";
+    let text = [0u8; 16];
+
+    let mut symtab = vec![0u8; 24];
+    let mut table = vec![0u8; 24];
+    table[0..4].copy_from_slice(&1u32.to_le_bytes());
+    table[4] = 0x10; // STB_GLOBAL, STT_NOTYPE
+    symtab.extend(table);
+    let mut section = vec![0u8; 24];
+    section[4] = STT_SECTION;
+    section[6..8].copy_from_slice(&1u16.to_le_bytes());
+    symtab.extend(section);
+
+    let mut rela = Vec::new();
+    for &(offset, sym, kind) in relocs {
+        rela.extend(offset.to_le_bytes());
+        rela.extend(((sym << 32) | kind).to_le_bytes());
+        rela.extend(0u64.to_le_bytes());
+    }
+
+    let mut data = vec![0u8; 64];
+    let mut contents = Vec::new();
+    for part in [&text[..], &rela, &symtab, strtab, shstrtab] {
+        contents.push((data.len() as u64, part.len() as u64));
+        data.extend(part);
+    }
+    let shoff = data.len() as u64;
+
+    // (name, type, flags, link, info)
+    let headers = [
+        (1, 1, 0x6, 0, 0),
+        (7, SHT_RELA, 0, 3, 1),
+        (18, SHT_SYMTAB, 0, 4, 2),
+        (26, 3, 0, 0, 0),
+        (34, 3, 0, 0, 0),
+    ];
+    data.extend([0u8; 64]);
+    for ((name, kind, flags, link, info), (offset, size)) in headers.iter().zip(&contents) {
+        let mut header = [0u8; 64];
+        header[0..4].copy_from_slice(&(*name as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&kind.to_le_bytes());
+        header[8..16].copy_from_slice(&(*flags as u64).to_le_bytes());
+        header[24..32].copy_from_slice(&offset.to_le_bytes());
+        header[32..40].copy_from_slice(&size.to_le_bytes());
+        header[40..44].copy_from_slice(&(*link as u32).to_le_bytes());
+        header[44..48].copy_from_slice(&(*info as u32).to_le_bytes());
+        data.extend(header);
+    }
+
+    data[..4].copy_from_slice(b"\x7fELF");
+    data[4] = 2;
+    data[5] = 1;
+    data[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+    data[40..48].copy_from_slice(&shoff.to_le_bytes());
+    data[58..60].copy_from_slice(&64u16.to_le_bytes());
+    data[60..62].copy_from_slice(&6u16.to_le_bytes());
+    data[62..64].copy_from_slice(&5u16.to_le_bytes());
+    data
+}
+
+#[test]
+fn test_absolute_relocations() {
+    // R_X86_64_PC32 and R_X86_64_PLT32 are fine, R_X86_64_32S and
+    // R_X86_64_64 in code are not.
+    let object = test_object(&[(0, 1, 2), (4, 1, 4), (8, 1, 11), (12, 2, 1)]);
+    let relocs = absolute_relocations(&object).unwrap();
+    assert_eq!(
+        relocs.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+        &[
+            "R_X86_64_32S against `table` in .text+0x8",
+            "R_X86_64_64 against `.text` in .text+0xc",
+        ]
+    );
+
+    assert!(absolute_relocations(&test_object(&[(0, 1, 2)]))
+        .unwrap()
+        .is_empty());
+    assert!(absolute_relocations(b"not an object").unwrap().is_empty());
+}
@@ -0,0 +1,160 @@
+//! Minimal glob support for collecting source files, so that `Build` doesn't
+//! need an extra dependency.
+//!
+//! Patterns are `/`-separated. `*` matches any run of characters within a
+//! component, `?` matches a single character and a `**` component matches any
+//! number of directories. Wildcards don't match names starting with `.`
+//! unless the pattern component does too.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Finds files below `root` matching `pattern`, sorted.
+///
+/// Returns the matches and the directory the search started from, which is
+/// the longest leading part of the pattern without wildcards.
+pub(crate) fn glob(root: &Path, pattern: &str) -> (Vec<PathBuf>, PathBuf) {
+    let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    let literal = components.iter().take_while(|c| !has_wildcard(c)).count();
+
+    let mut base = if pattern.starts_with('/') {
+        PathBuf::from("/")
+    } else {
+        root.to_owned()
+    };
+    for component in &components[..literal] {
+        base.push(component);
+    }
+
+    let mut matches = Vec::new();
+    if literal == components.len() {
+        if base.is_file() {
+            matches.push(base.clone());
+        }
+        base.pop();
+    } else {
+        walk(&base, &components[literal..], &mut matches);
+    }
+
+    matches.sort();
+    matches.dedup();
+    (matches, base)
+}
+
+fn walk(dir: &Path, components: &[&str], matches: &mut Vec<PathBuf>) {
+    let (component, rest) = match components.split_first() {
+        Some(split) => split,
+        None => {
+            if dir.is_file() {
+                matches.push(dir.to_owned());
+            }
+            return;
+        }
+    };
+
+    if *component == "**" {
+        walk(dir, rest, matches);
+        for entry in read_dir_sorted(dir) {
+            if entry.is_dir() && !is_hidden(&entry) {
+                walk(&entry, components, matches);
+            }
+        }
+    } else if has_wildcard(component) {
+        for entry in read_dir_sorted(dir) {
+            let name = match entry.file_name().and_then(|n| n.to_str()) {
+                Some(name) => name,
+                None => continue,
+            };
+            if name.starts_with('.') && !component.starts_with('.') {
+                continue;
+            }
+            if wildcard_match(component.as_bytes(), name.as_bytes()) {
+                walk(&entry, rest, matches);
+            }
+        }
+    } else {
+        walk(&dir.join(component), rest, matches);
+    }
+}
+
+fn read_dir_sorted(dir: &Path) -> Vec<PathBuf> {
+    let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(_) => Vec::new(),
+    };
+    entries.sort();
+    entries
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with('.'))
+}
+
+fn has_wildcard(component: &str) -> bool {
+    component.contains(['*', '?'])
+}
+
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            wildcard_match(rest, name) || (!name.is_empty() && wildcard_match(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((_, name_rest))) => wildcard_match(rest, name_rest),
+        (Some((p, rest)), Some((n, name_rest))) if p == n => wildcard_match(rest, name_rest),
+        _ => false,
+    }
+}
+
+#[test]
+fn test_wildcard_match() {
+    assert!(wildcard_match(b"*.asm", b"foo.asm"));
+    assert!(wildcard_match(b"f?o*.asm", b"foo_avx2.asm"));
+    assert!(!wildcard_match(b"*.asm", b"foo.inc"));
+    assert!(!wildcard_match(b"foo", b"foobar"));
+}
+
+#[test]
+fn test_glob() {
+    let root = std::env::temp_dir().join("nasm-rs-test-glob");
+    let _ = fs::remove_dir_all(&root);
+    for file in [
+        "src/asm/b.asm",
+        "src/asm/a.asm",
+        "src/asm/x86_64/c.asm",
+        "src/asm/x86_64/c.inc",
+        "src/asm/.hidden/d.asm",
+    ] {
+        let path = root.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "").unwrap();
+    }
+
+    let (files, base) = glob(&root, "src/asm/**/*.asm");
+    assert_eq!(base, root.join("src/asm"));
+    assert_eq!(
+        files,
+        vec![
+            root.join("src/asm/a.asm"),
+            root.join("src/asm/b.asm"),
+            root.join("src/asm/x86_64/c.asm"),
+        ]
+    );
+
+    let (files, _) = glob(&root, "src/*/x86_64/c.*");
+    assert_eq!(
+        files,
+        vec![
+            root.join("src/asm/x86_64/c.asm"),
+            root.join("src/asm/x86_64/c.inc"),
+        ]
+    );
+
+    let (files, base) = glob(&root, "src/asm/a.asm");
+    assert_eq!(files, vec![root.join("src/asm/a.asm")]);
+    assert_eq!(base, root.join("src/asm"));
+
+    let _ = fs::remove_dir_all(&root);
+}
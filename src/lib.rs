@@ -1,56 +1,114 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
 use std::env;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use std::process::Stdio;
-use std::{sync, thread, time};
+use std::process::{Command, ExitStatus};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
 
 #[cfg(feature = "parallel")]
 use std::sync::OnceLock;
+#[cfg(feature = "parallel")]
+use std::{sync, thread, time};
+
+#[cfg(feature = "parallel")]
+use log::error;
+use log::info;
+
+mod assembler;
+mod coff;
+mod constants;
+mod elf;
+mod glob;
+mod target;
+
+pub use assembler::{Assembler, Diagnostic, Severity, WarningLevel};
+pub use constants::Constants;
+pub use target::{Arch, Target};
+
+use assembler::{parse_dependencies, DependencyArgs};
 
-use log::{error, info};
+/// `cargo:rerun-if-*` lines already printed, so each is printed once.
+static RERUN_LINES: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
 
 #[cfg(feature = "parallel")]
 static JOBSERVER: OnceLock<jobserver::Client> = OnceLock::new();
 
 fn x86_triple(os: &str) -> (&'static str, &'static str) {
     match os {
-        "darwin" | "ios" => ("-fmacho32", "-g"),
-        "windows" | "uefi" => ("-fwin32", "-g"),
+        "macos" | "ios" | "tvos" | "watchos" | "visionos" => ("-fmacho32", "-gdwarf"),
+        "windows" | "uefi" => ("-fwin32", "-gcv8"),
         _ => ("-felf32", "-gdwarf"),
     }
 }
 
 fn x86_64_triple(os: &str) -> (&'static str, &'static str) {
     match os {
-        "darwin" | "ios" => ("-fmacho64", "-g"),
-        "windows" | "uefi" => ("-fwin64", "-g"),
+        "macos" | "ios" | "tvos" | "watchos" | "visionos" => ("-fmacho64", "-gdwarf"),
+        "windows" | "uefi" => ("-fwin64", "-gcv8"),
         _ => ("-felf64", "-gdwarf"),
     }
 }
 
-fn parse_triple(trip: &str) -> (&'static str, &'static str) {
-    let parts = trip.split('-').collect::<Vec<_>>();
-    // ARCH-VENDOR-OS-ENVIRONMENT
-    // or ARCH-VENDOR-OS
-    // we don't care about environ (yes, we do... gnux32) so doesn't matter if triple doesn't have it
-    if parts.len() < 3 {
+/// Output format and debug format flags for `target`.
+fn object_format(target: &Target) -> (&'static str, &'static str) {
+    if target.os.is_empty() {
         return ("", "-g");
     }
 
-    match parts[0] {
-        "x86_64" => {
-            if parts.len() >= 4 && parts[3] == "gnux32" {
+    match target.arch {
+        Arch::X86_64 => {
+            if target.pointer_width == 32 {
                 ("-felfx32", "-gdwarf")
             } else {
-                x86_64_triple(parts[2])
+                x86_64_triple(&target.os)
             }
         }
-        "x86" | "i386" | "i586" | "i686" => x86_triple(parts[2]),
-        _ => ("", "-g"),
+        Arch::X86 => x86_triple(&target.os),
+        Arch::Other(_) => ("", "-g"),
     }
 }
 
+/// Operating systems whose executables are position independent by default.
+const HOSTED_OSES: &[&str] = &[
+    "linux",
+    "android",
+    "freebsd",
+    "netbsd",
+    "openbsd",
+    "dragonfly",
+    "illumos",
+    "solaris",
+    "fuchsia",
+    "haiku",
+    "macos",
+    "ios",
+    "tvos",
+    "watchos",
+    "visionos",
+];
+
+/// Decides whether a file added with [`Build::file_for_target`] is assembled.
+type TargetPredicate = Arc<dyn Fn(&Target) -> bool + Send + Sync>;
+
+struct SourceFile {
+    path: PathBuf,
+    condition: Option<TargetPredicate>,
+}
+
+/// A file included before every source, see [`Build::pre_include`].
+enum PreInclude {
+    File(PathBuf),
+    /// Generated text, written to `OUT_DIR` before assembling.
+    Prelude(String),
+}
+
 /// # Example
 ///
 /// ```no_run
@@ -81,34 +139,73 @@ pub fn compile_library_args<P: AsRef<Path>>(
 }
 
 pub struct Build {
-    files: Vec<PathBuf>,
-    flags: Vec<String>,
+    files: Vec<SourceFile>,
+    flags: Vec<OsString>,
+    includes: Vec<PathBuf>,
+    pre_includes: Vec<PreInclude>,
+    constants: Vec<Constants>,
     target: Option<String>,
     out_dir: Option<PathBuf>,
     archiver: Option<PathBuf>,
     archiver_is_msvc: Option<bool>,
     nasm: Option<PathBuf>,
-    debug: bool,
+    assembler: Assembler,
+    debug_info: DebugInfo,
+    track_dependencies: Option<bool>,
     min_version: (usize, usize, usize),
+    max_version: Option<(usize, usize, usize)>,
+    probe_cache: Mutex<HashMap<(PathBuf, String, String), bool>>,
+    optional: bool,
+    unavailable_cfg: String,
+    compile_commands: bool,
+    compile_commands_path: Option<PathBuf>,
+    warnings: Vec<(String, WarningLevel)>,
+    warnings_into_errors: Option<bool>,
+    response_file_threshold: usize,
+    opt_level: Option<String>,
+    file_opt_levels: HashMap<PathBuf, String>,
+    pic: Option<bool>,
+    check_relocations: bool,
+    check_unwind_info: bool,
+}
+
+impl Default for Build {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Build {
     pub fn new() -> Self {
-        let nasm = match env::var("NASM") {
-            Ok(n) => Some(PathBuf::from(n)),
-            Err(_) => None,
-        };
-
         Self {
             files: Vec::new(),
             flags: Vec::new(),
+            includes: Vec::new(),
+            pre_includes: Vec::new(),
+            constants: Vec::new(),
             archiver: None,
             archiver_is_msvc: None,
             out_dir: None,
-            nasm,
+            nasm: None,
+            assembler: Assembler::Nasm,
+            track_dependencies: None,
             target: None,
             min_version: (1, 0, 0),
-            debug: env::var("DEBUG").ok().map_or(false, |d| d != "false"),
+            max_version: None,
+            probe_cache: Mutex::new(HashMap::new()),
+            optional: false,
+            unavailable_cfg: "nasm_rs_unavailable".to_owned(),
+            compile_commands: false,
+            compile_commands_path: None,
+            warnings: Vec::new(),
+            warnings_into_errors: None,
+            response_file_threshold: 8 * 1024,
+            opt_level: None,
+            file_opt_levels: HashMap::new(),
+            debug_info: debug_info_from_env(),
+            pic: None,
+            check_relocations: false,
+            check_unwind_info: false,
         }
     }
 
@@ -116,7 +213,10 @@ impl Build {
     ///
     /// e.g. `"foo.s"`
     pub fn file<P: AsRef<Path>>(&mut self, p: P) -> &mut Self {
-        self.files.push(p.as_ref().to_owned());
+        self.files.push(SourceFile {
+            path: p.as_ref().to_owned(),
+            condition: None,
+        });
         self
     }
 
@@ -128,25 +228,169 @@ impl Build {
         self
     }
 
+    /// Add a file which will only be compiled for targets of architecture `arch`
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use nasm_rs::{Arch, Build};
+    ///
+    /// Build::new()
+    ///     .file_for_arch(Arch::X86, "src/x86/memcpy.asm")
+    ///     .file_for_arch(Arch::X86_64, "src/x86_64/memcpy.asm")
+    ///     .compile("memcpy")
+    ///     .unwrap();
+    /// ```
+    pub fn file_for_arch<P: AsRef<Path>>(&mut self, arch: Arch, p: P) -> &mut Self {
+        self.file_for_target(move |target| target.arch == arch, p)
+    }
+
+    /// Add a file which will only be compiled for targets matching `predicate`
+    ///
+    /// The predicate is evaluated when compiling, so it sees the final target
+    /// even if [`Build::target`] is called afterwards.
+    pub fn file_for_target<F, P>(&mut self, predicate: F, p: P) -> &mut Self
+    where
+        F: Fn(&Target) -> bool + Send + Sync + 'static,
+        P: AsRef<Path>,
+    {
+        self.files.push(SourceFile {
+            path: p.as_ref().to_owned(),
+            condition: Some(Arc::new(predicate)),
+        });
+        self
+    }
+
+    /// Add every file matching a glob pattern, e.g. `"src/asm/**/*.asm"`
+    ///
+    /// Relative patterns are resolved against `CARGO_MANIFEST_DIR`. Matches
+    /// are added in sorted order, and are registered with
+    /// `cargo:rerun-if-changed` along with the directory searched, so that
+    /// added or removed files rerun the build script.
+    ///
+    /// `*` and `?` match within a path component and `**` matches any number
+    /// of directories.
+    pub fn files_glob(&mut self, pattern: &str) -> &mut Self {
+        let src = self.get_src_dir();
+        let (files, base) = glob::glob(&src, pattern);
+        self.add_collected_files(&src, files, &base, pattern)
+    }
+
+    /// Add every file in `dir` with one of the given extensions, e.g.
+    /// `&["asm", "s"]`
+    ///
+    /// Subdirectories are not searched; use [`Build::files_glob`] for that.
+    /// Relative directories are resolved and files registered with Cargo like
+    /// [`Build::files_glob`] does.
+    pub fn dir<P: AsRef<Path>>(&mut self, dir: P, extensions: &[&str]) -> &mut Self {
+        let src = self.get_src_dir();
+        let dir = self.resolve_path(dir.as_ref());
+        let mut files: Vec<PathBuf> = std::fs::read_dir(&dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.path())
+                    .filter(|p| p.is_file())
+                    .filter(|p| {
+                        p.extension()
+                            .and_then(|e| e.to_str())
+                            .is_some_and(|e| extensions.contains(&e))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        files.sort();
+        let description = dir.display().to_string();
+        self.add_collected_files(&src, files, &dir, &description)
+    }
+
+    fn add_collected_files(
+        &mut self,
+        src: &Path,
+        files: Vec<PathBuf>,
+        searched: &Path,
+        description: &str,
+    ) -> &mut Self {
+        rerun_if_changed(searched);
+        if files.is_empty() {
+            println!("cargo:warning=No assembly sources found in {}", description);
+        }
+        for file in files {
+            rerun_if_changed(&file);
+            let file = match file.strip_prefix(src) {
+                Ok(relative) => relative.to_owned(),
+                Err(_) => file,
+            };
+            self.file(file);
+        }
+        self
+    }
+
     /// Add a directory to the `-I` include path
+    ///
+    /// Relative directories are resolved against `CARGO_MANIFEST_DIR`, like
+    /// source files.
     pub fn include<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
-        let mut flag = format!("-I{}", dir.as_ref().display());
-        // nasm requires trailing slash, but `Path` may omit it.
-        if !flag.ends_with('/') {
-            flag += "/";
-        }
-        self.flags.push(flag);
+        self.includes.push(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Include a file before every source, like `%include` on its first line
+    ///
+    /// Relative paths are resolved against `CARGO_MANIFEST_DIR`, like source
+    /// files. Files are included in the order they were added.
+    pub fn pre_include<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.pre_includes
+            .push(PreInclude::File(path.as_ref().to_owned()));
+        self
+    }
+
+    /// Include generated text before every source
+    ///
+    /// The text is written to a file in `OUT_DIR` when compiling, so build
+    /// scripts can use it to pass computed constants or feature defines to
+    /// the assembly.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// let has_avx2 = std::env::var("CARGO_CFG_TARGET_FEATURE")
+    ///     .is_ok_and(|f| f.split(',').any(|f| f == "avx2"));
+    ///
+    /// nasm_rs::Build::new()
+    ///     .prelude(&format!("%define HAVE_AVX2 {}\n", has_avx2 as u8))
+    ///     .file("src/simd.asm")
+    ///     .compile("simd")
+    ///     .unwrap();
+    /// ```
+    pub fn prelude(&mut self, text: &str) -> &mut Self {
+        self.pre_includes.push(PreInclude::Prelude(text.to_owned()));
+        self
+    }
+
+    /// Generate an include file of constants when compiling
+    ///
+    /// The file is written to a directory in `OUT_DIR` which is added to the
+    /// include path, so sources can `%include` it by
+    /// [file name](Constants::file_name).
+    pub fn constants(&mut self, constants: &Constants) -> &mut Self {
+        self.constants.push(constants.clone());
         self
     }
 
     /// Pre-define a macro with an optional value
     pub fn define<'a, V: Into<Option<&'a str>>>(&mut self, var: &str, val: V) -> &mut Self {
-        let val = val.into();
-        let flag = if let Some(val) = val {
-            format!("-D{}={}", var, val)
-        } else {
-            format!("-D{}", var)
-        };
+        self.define_os(var, val.into().map(OsStr::new))
+    }
+
+    /// Pre-define a macro with an optional value which need not be UTF-8,
+    /// such as a path
+    pub fn define_os<'a, V: Into<Option<&'a OsStr>>>(&mut self, var: &str, val: V) -> &mut Self {
+        let mut flag = OsString::from(format!("-D{}", var));
+        if let Some(val) = val.into() {
+            flag.push("=");
+            flag.push(val);
+        }
         self.flags.push(flag);
         self
     }
@@ -157,7 +401,98 @@ impl Build {
     /// variable by build scripts (only enabled when the profile is "debug"), so
     /// it's not required to call this function.
     pub fn debug(&mut self, enable: bool) -> &mut Self {
-        self.debug = enable;
+        self.debug_info = if enable {
+            DebugInfo::Full
+        } else {
+            DebugInfo::None
+        };
+        self
+    }
+
+    /// Configures the level of debug information generated.
+    ///
+    /// This option is automatically scraped from the `DEBUG` environment
+    /// variable, which Cargo sets from the profile's `debug` setting. CodeView
+    /// is generated for Windows targets and DWARF otherwise. NASM has no
+    /// reduced debug info, so every level other than [`DebugInfo::None`]
+    /// produces full line and symbol information.
+    ///
+    /// `-Csplit-debuginfo` needs no handling here: the debug information
+    /// stays in the objects, and the linker or `dsymutil` splits it out of the
+    /// final binary along with Rust's own.
+    pub fn debug_info(&mut self, debug_info: DebugInfo) -> &mut Self {
+        self.debug_info = debug_info;
+        self
+    }
+
+    /// Configures whether sources are assembled as position independent code.
+    ///
+    /// Enabling this defines `PIC` and, for 64-bit x86 with NASM, makes memory
+    /// operands RIP-relative by default (`default rel`), which PIE executables
+    /// and shared objects need.
+    ///
+    /// If this isn't called, only `PIC` is defined, and only for hosted
+    /// targets such as Linux, Android, the BSDs and macOS, unless
+    /// `-C relocation-model=static` is present in `CARGO_ENCODED_RUSTFLAGS`.
+    /// Freestanding targets like `none` and `uefi` often rely on absolute
+    /// addressing, so nothing is added for them.
+    pub fn pic(&mut self, enable: bool) -> &mut Self {
+        self.pic = Some(enable);
+        self
+    }
+
+    /// Configures whether ELF objects are checked for absolute relocations
+    /// after assembling position independent code.
+    ///
+    /// Such relocations make the linker fail with errors like "relocation
+    /// R_X86_64_32 against ... can not be used when making a PIE object";
+    /// this reports them when building the crate instead. Disabled by default.
+    pub fn check_relocations(&mut self, enable: bool) -> &mut Self {
+        self.check_relocations = enable;
+        self
+    }
+
+    /// Configures whether 64-bit Windows objects are checked for exported
+    /// functions without unwind information.
+    ///
+    /// Each function missing a `.pdata` entry is reported as a build warning,
+    /// since panics and profilers can't unwind through it. The macros in
+    /// `nasm-rs/unwind.inc`, which is always on the include path, generate
+    /// these entries for pushes, stack allocations, saved XMM registers and
+    /// frame pointers. Leaf functions that don't touch the stack don't need
+    /// them. Disabled by default.
+    pub fn check_unwind_info(&mut self, enable: bool) -> &mut Self {
+        self.check_unwind_info = enable;
+        self
+    }
+
+    /// Configures the optimization level of the assembler.
+    ///
+    /// Levels follow Cargo's `opt-level`: `0` disables branch offset
+    /// optimization (`-O0`), `1` only does minimal optimization (`-O1`), and
+    /// anything higher uses NASM's multi-pass optimization (`-Ox`).
+    ///
+    /// This option is automatically scraped from the `OPT_LEVEL` environment
+    /// variable by build scripts, so it's not required to call this function.
+    pub fn opt_level(&mut self, opt_level: u32) -> &mut Self {
+        self.opt_level = Some(opt_level.to_string());
+        self
+    }
+
+    /// Configures the optimization level of the assembler using a string,
+    /// to accept Cargo's `"s"` and `"z"` levels as well.
+    pub fn opt_level_str(&mut self, opt_level: &str) -> &mut Self {
+        self.opt_level = Some(opt_level.to_owned());
+        self
+    }
+
+    /// Overrides the optimization level for a single file, e.g. one which
+    /// must be assembled with `-O0` for exact instruction encodings.
+    ///
+    /// The path must match the one given to [`Build::file`].
+    pub fn file_opt_level<P: AsRef<Path>>(&mut self, file: P, opt_level: &str) -> &mut Self {
+        self.file_opt_levels
+            .insert(file.as_ref().to_owned(), opt_level.to_owned());
         self
     }
 
@@ -165,7 +500,43 @@ impl Build {
     ///
     /// e.g. `"-Fdwarf"`
     pub fn flag(&mut self, flag: &str) -> &mut Self {
-        self.flags.push(flag.to_owned());
+        self.flag_os(flag)
+    }
+
+    /// Add an arbitrary flag which need not be UTF-8, such as one containing
+    /// a path
+    pub fn flag_os<S: AsRef<OsStr>>(&mut self, flag: S) -> &mut Self {
+        self.flags.push(flag.as_ref().to_owned());
+        self
+    }
+
+    /// Configures a class of assembler warnings, e.g. `"orphan-labels"`.
+    ///
+    /// See the NASM manual for the available classes. YASM has no per-class
+    /// errors, so [`WarningLevel::Error`] only enables the class there.
+    pub fn warning(&mut self, class: &str, level: WarningLevel) -> &mut Self {
+        self.warnings.push((class.to_owned(), level));
+        self
+    }
+
+    /// Configures whether all warnings are treated as errors.
+    ///
+    /// Defaults to enabled when the `NASM_RS_WERROR` environment variable is
+    /// set to a value other than `0` or `false`, or when the crate is built
+    /// with `--cfg nasm_rs_werror` in `RUSTFLAGS`, so CI can enforce clean
+    /// assembly without changing build scripts.
+    pub fn warnings_into_errors(&mut self, enable: bool) -> &mut Self {
+        self.warnings_into_errors = Some(enable);
+        self
+    }
+
+    /// Configures the command line length, in bytes, above which arguments
+    /// are passed to NASM through a `-@` response file instead.
+    ///
+    /// Defaults to 8 KiB, which is safe on every platform. Pass `usize::MAX`
+    /// to never use response files. YASM doesn't support them.
+    pub fn response_file_threshold(&mut self, threshold: usize) -> &mut Self {
+        self.response_file_threshold = threshold;
         self
     }
 
@@ -199,7 +570,8 @@ impl Build {
 
     /// Configures the default archiver tool as well as the command syntax.
     ///
-    /// This option is automatically determined from `cfg!(target_env = "msvc")`,
+    /// This option is automatically determined from the target's environment
+    /// (`CARGO_CFG_TARGET_ENV`, or the `-msvc` suffix of the target triple),
     /// so it's not required to call this function.
     pub fn archiver_is_msvc(&mut self, is_msvc: bool) -> &mut Self {
         self.archiver_is_msvc = Some(is_msvc);
@@ -207,17 +579,160 @@ impl Build {
     }
 
     /// Configures path to `nasm` command
+    ///
+    /// If this is not set, the `NASM_<target>`, `NASM_<target_with_underscores>`,
+    /// `TARGET_NASM` (or `HOST_NASM`) and `NASM` environment variables are
+    /// consulted, followed by the directories in `NASM_RS_SEARCH_PATH` and `PATH`.
     pub fn nasm<P: AsRef<Path>>(&mut self, nasm: P) -> &mut Self {
         self.nasm = Some(nasm.as_ref().to_owned());
         self
     }
 
+    /// Selects the assembler used to build the sources. Defaults to NASM.
+    ///
+    /// The path set with [`Build::nasm`] is used for whichever assembler is
+    /// selected. Otherwise YASM is looked up like NASM, but with `YASM_*`
+    /// environment variables.
+    pub fn assembler(&mut self, assembler: Assembler) -> &mut Self {
+        self.assembler = assembler;
+        self
+    }
+
+    /// Emit `cargo:rerun-if-changed` for every file included by the sources,
+    /// as reported by the assembler.
+    ///
+    /// Sources, include directories and pre-included files are always
+    /// registered. Enabled by default with NASM, which writes the dependencies
+    /// while assembling. Disabled by default with YASM, since it needs an extra
+    /// assembler run.
+    pub fn track_dependencies(&mut self, enable: bool) -> &mut Self {
+        self.track_dependencies = Some(enable);
+        self
+    }
+
     /// Set the minimum version required
     pub fn min_version(&mut self, major: usize, minor: usize, micro: usize) -> &mut Self {
         self.min_version = (major, minor, micro);
         self
     }
 
+    /// Set the maximum version accepted
+    ///
+    /// Patch levels and release candidates of the maximum version are accepted too.
+    pub fn max_version(&mut self, major: usize, minor: usize, micro: usize) -> &mut Self {
+        self.max_version = Some((major, minor, micro));
+        self
+    }
+
+    /// Returns the version of the assembler that will be used,
+    /// locating it first if necessary.
+    pub fn nasm_version(&self) -> Result<NasmVersion, String> {
+        self.locate_nasm_with_version()
+            .map(|(_, version)| version)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Checks whether the assembler accepts `snippet` when assembling for the
+    /// configured target.
+    ///
+    /// The snippet is assembled into a scratch file in the output directory.
+    /// Results are cached per assembler executable and target, so repeated
+    /// probes are cheap. Returns `false` if no usable assembler is found.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// let mut build = nasm_rs::Build::new();
+    /// if build.probe_instruction("vpdpbusd zmm0, zmm1, zmm2") {
+    ///     build.define("HAVE_AVX512_VNNI", None);
+    /// }
+    /// ```
+    pub fn probe_instruction(&self, snippet: &str) -> bool {
+        let nasm = match self.locate_nasm() {
+            Ok(nasm) => nasm,
+            Err(_) => return false,
+        };
+        let target = self.get_target();
+        let key = (nasm, target, snippet.to_owned());
+        if let Some(&supported) = self.probe_cache.lock().unwrap().get(&key) {
+            return supported;
+        }
+
+        let supported = self.assemble_probe(&key.0, &key.1, snippet);
+        self.probe_cache.lock().unwrap().insert(key, supported);
+        supported
+    }
+
+    /// Checks whether the assembler supports the instruction set `feature`
+    /// for the configured target. See [`Build::probe_instruction`].
+    pub fn nasm_supports(&self, feature: InstructionSet) -> bool {
+        self.probe_instruction(feature.probe_snippet())
+    }
+
+    fn assemble_probe(&self, nasm: &Path, target: &str, snippet: &str) -> bool {
+        static PROBE_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let dir = self.get_out_dir().join("nasm-rs-probe");
+        let id = PROBE_ID.fetch_add(1, AtomicOrdering::Relaxed);
+        let src = dir.join(format!("probe-{}-{}.asm", std::process::id(), id));
+        let obj = src.with_extension("o");
+        if std::fs::create_dir_all(&dir).is_err()
+            || std::fs::write(&src, format!("section .text\n{}\n", snippet)).is_err()
+        {
+            return false;
+        }
+
+        let mut cmd = Command::new(nasm);
+        cmd.args(self.assembler.target_args(&Target::resolve(target), false))
+            .arg(&src)
+            .arg("-o")
+            .arg(&obj)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        info!("probing: {:?}", cmd);
+        let supported = cmd.status().is_ok_and(|status| status.success());
+
+        let _ = std::fs::remove_file(&src);
+        let _ = std::fs::remove_file(&obj);
+        supported
+    }
+
+    /// Configures whether a missing or unsuitable assembler is an error.
+    ///
+    /// When enabled, [`Build::compile`] and [`Build::compile_objects`] behave
+    /// like [`Build::try_compile`] and [`Build::try_compile_objects`]: instead
+    /// of failing, they emit a `cargo:warning`, set the `cfg` configured with
+    /// [`Build::unavailable_cfg`] and produce nothing, so Rust fallback code
+    /// can be used.
+    pub fn optional(&mut self, optional: bool) -> &mut Self {
+        self.optional = optional;
+        self
+    }
+
+    /// Name of the `cfg` set for the crate when the assembler is unavailable.
+    ///
+    /// Defaults to `nasm_rs_unavailable`.
+    pub fn unavailable_cfg(&mut self, name: &str) -> &mut Self {
+        self.unavailable_cfg = name.to_owned();
+        self
+    }
+
+    /// Configures whether a `compile_commands.json` compilation database
+    /// describing every assembled file is written to the output directory.
+    pub fn compile_commands(&mut self, enable: bool) -> &mut Self {
+        self.compile_commands = enable;
+        self
+    }
+
+    /// Additionally writes the compilation database to `path`, e.g. the
+    /// workspace root where editors look for it. Implies
+    /// [`Build::compile_commands`].
+    pub fn compile_commands_path<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.compile_commands = true;
+        self.compile_commands_path = Some(path.as_ref().to_owned());
+        self
+    }
+
     /// Run the compiler, generating the file output
     ///
     /// The name output should be the base name of the library,
@@ -226,6 +741,36 @@ impl Build {
     /// The output file will have target-specific name,
     /// such as `lib*.a` (non-MSVC) or `*.lib` (MSVC).
     pub fn compile(&mut self, lib_name: &str) -> Result<(), String> {
+        let optional = self.optional;
+        self.compile_optional(lib_name, optional).map(|_| ())
+    }
+
+    /// Like [`Build::compile`], but returns `Ok(None)` if the assembler is
+    /// missing or too old, after setting the `cfg` configured with
+    /// [`Build::unavailable_cfg`].
+    ///
+    /// On success, returns the path of the library.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// let built = nasm_rs::Build::new()
+    ///     .file("src/simd.asm")
+    ///     .try_compile("simd")
+    ///     .unwrap();
+    /// if built.is_none() {
+    ///     // `#[cfg(nasm_rs_unavailable)]` selects the Rust implementation.
+    /// }
+    /// ```
+    pub fn try_compile(&mut self, lib_name: &str) -> Result<Option<PathBuf>, String> {
+        self.compile_optional(lib_name, true)
+    }
+
+    fn compile_optional(
+        &mut self,
+        lib_name: &str,
+        graceful: bool,
+    ) -> Result<Option<PathBuf>, String> {
         // Trim name for backwards comatibility
         let lib_name = if lib_name.starts_with("lib") && lib_name.ends_with(".a") {
             &lib_name[3..lib_name.len() - 2]
@@ -234,35 +779,81 @@ impl Build {
         };
 
         let target = self.get_target();
-        let output = if target.ends_with("-msvc") {
+        let output = if is_msvc_target(&target) {
             format!("{}.lib", lib_name)
         } else {
             format!("lib{}.a", lib_name)
         };
 
         let dst = &self.get_out_dir();
-        let objects = self.compile_objects()?;
+        let objects = match self.compile_objects_optional(graceful)? {
+            Some(objects) => objects,
+            None => return Ok(None),
+        };
         self.archive(dst, &output, &objects[..])?;
 
         println!("cargo:rustc-link-search={}", dst.display());
-        Ok(())
+        Ok(Some(dst.join(output)))
     }
 
     /// Run the compiler, generating .o files
     ///
     /// The files can be linked in a separate step, e.g. passed to `cc`
     pub fn compile_objects(&mut self) -> Result<Vec<PathBuf>, String> {
+        let optional = self.optional;
+        Ok(self.compile_objects_optional(optional)?.unwrap_or_default())
+    }
+
+    /// Like [`Build::compile_objects`], but returns `Ok(None)` if the
+    /// assembler is missing or too old. See [`Build::try_compile`].
+    pub fn try_compile_objects(&mut self) -> Result<Option<Vec<PathBuf>>, String> {
+        self.compile_objects_optional(true)
+    }
+
+    fn compile_objects_optional(&mut self, graceful: bool) -> Result<Option<Vec<PathBuf>>, String> {
         let target = self.get_target();
 
-        let nasm = self.find_nasm()?;
+        let nasm = match self.find_nasm(graceful)? {
+            Some(nasm) => nasm,
+            None => return Ok(None),
+        };
+        self.write_generated()?;
         let args = self.get_args(&target);
 
-        let src = &PathBuf::from(
-            env::var_os("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR must be set"),
-        );
+        let src = &self.get_src_dir();
         let dst = &self.get_out_dir();
 
-        self.compile_objects_inner(&nasm, &self.files, &args, src, dst)
+        let files = self.get_files(&target);
+
+        if self.compile_commands {
+            let invocations = files
+                .iter()
+                .map(|file| self.invocation(&nasm, file, &args, src, dst))
+                .collect::<Vec<_>>();
+            self.write_compile_commands(&invocations, src, dst)?;
+        }
+
+        self.compile_objects_inner(&nasm, &files, &args, src, dst)
+            .map(Some)
+    }
+
+    fn write_compile_commands(
+        &self,
+        invocations: &[Invocation],
+        src: &Path,
+        dst: &Path,
+    ) -> Result<(), String> {
+        let json = compile_commands_json(invocations, src);
+        let paths = std::iter::once(dst.join("compile_commands.json"))
+            .chain(self.compile_commands_path.iter().cloned());
+        for path in paths {
+            if let Some(parent) = path.parent() {
+                let _ = std::fs::create_dir_all(parent);
+            }
+            std::fs::write(&path, &json)
+                .map_err(|e| format!("Unable to write {}: {}", path.display(), e))?;
+        }
+        Ok(())
     }
 
     #[cfg(feature = "parallel")]
@@ -270,7 +861,7 @@ impl Build {
         &self,
         nasm: &Path,
         files: &[PathBuf],
-        args: &[&str],
+        args: &[OsString],
         src: &Path,
         dst: &Path,
     ) -> Result<Vec<PathBuf>, String> {
@@ -399,156 +990,1303 @@ impl Build {
         &self,
         nasm: &Path,
         files: &[PathBuf],
-        args: &[&str],
+        args: &[OsString],
         src: &Path,
         dst: &Path,
     ) -> Result<Vec<PathBuf>, String> {
         files
             .iter()
-            .map(|file| self.compile_file(&nasm, file, &args, src, dst))
+            .map(|file| self.compile_file(nasm, file, args, src, dst))
             .collect()
     }
 
-    fn get_args(&self, target: &str) -> Vec<&str> {
-        let (arch_flag, debug_flag) = parse_triple(target);
-        let mut args = vec![arch_flag];
+    fn get_args(&self, target: &str) -> Vec<OsString> {
+        let target = Target::resolve(target);
+        let mut args: Vec<OsString> = self
+            .assembler
+            .target_args(&target, self.debug_info != DebugInfo::None)
+            .into_iter()
+            .map(OsString::from)
+            .collect();
+
+        if self.get_pic(&target) {
+            args.push("-DPIC".into());
+            if self.pic == Some(true) {
+                args.extend(
+                    self.assembler
+                        .default_rel_args(&target)
+                        .iter()
+                        .map(OsString::from),
+                );
+            }
+        }
 
-        if self.debug {
-            args.push(debug_flag);
+        for (class, level) in &self.warnings {
+            args.extend(
+                self.assembler
+                    .warning_args(class, *level)
+                    .map(OsString::from),
+            );
+        }
+        if self
+            .warnings_into_errors
+            .unwrap_or_else(warnings_into_errors_from_env)
+        {
+            args.push(self.assembler.warnings_into_errors_flag().into());
         }
 
-        for arg in &self.flags {
+        for dir in &self.includes {
+            args.push(include_arg(&self.resolve_path(dir)));
+        }
+        args.push(include_arg(&self.generated_include_dir()));
+        for pre_include in &self.pre_includes {
+            let mut arg = OsString::from("-P");
+            arg.push(self.pre_include_path(pre_include));
             args.push(arg);
         }
 
-        args
-    }
-
-    fn compile_file(
-        &self,
-        nasm: &Path,
-        file: &Path,
-        new_args: &[&str],
-        src: &Path,
-        dst: &Path,
-    ) -> Result<PathBuf, String> {
-        let obj = dst.join(file.file_name().unwrap()).with_extension("o");
-        let mut cmd = Command::new(nasm);
-        cmd.args(new_args);
-        std::fs::create_dir_all(obj.parent().unwrap()).unwrap();
+        args.extend(self.flags.iter().cloned());
 
-        run(cmd.arg(src.join(file)).arg("-o").arg(&obj))?;
-        Ok(obj)
+        args
     }
 
-    fn archive(&self, out_dir: &Path, lib: &str, objs: &[PathBuf]) -> Result<(), String> {
-        let ar_is_msvc = self.archiver_is_msvc.unwrap_or(cfg!(target_env = "msvc"));
-
-        let ar = if ar_is_msvc {
-            self.archiver.clone().unwrap_or_else(|| "lib".into())
-        } else {
-            self.archiver
-                .clone()
-                .or_else(|| env::var_os("AR").map(|a| a.into()))
-                .unwrap_or_else(|| "ar".into())
-        };
-        if ar_is_msvc {
-            let mut out_param = OsString::new();
-            out_param.push("/OUT:");
-            out_param.push(out_dir.join(lib).as_os_str());
-            run(Command::new(ar).arg(out_param).args(objs))
-        } else {
-            run(Command::new(ar)
-                .arg("crus")
-                .arg(out_dir.join(lib))
-                .args(objs))
+    fn pre_include_path(&self, pre_include: &PreInclude) -> PathBuf {
+        match pre_include {
+            PreInclude::File(path) => self.resolve_path(path),
+            PreInclude::Prelude(text) => {
+                // Named after the contents, so that builds sharing `OUT_DIR`
+                // don't overwrite each other's preludes.
+                let mut hasher = DefaultHasher::new();
+                text.hash(&mut hasher);
+                self.get_out_dir()
+                    .join(format!("nasm-rs-prelude-{:016x}.inc", hasher.finish()))
+            }
         }
     }
 
-    fn get_out_dir(&self) -> PathBuf {
-        self.out_dir
-            .clone()
-            .unwrap_or_else(|| PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR must be set")))
+    fn generated_include_dir(&self) -> PathBuf {
+        self.get_out_dir().join("nasm-rs-include")
     }
 
-    fn get_target(&self) -> String {
-        self.target
-            .clone()
-            .unwrap_or_else(|| env::var("TARGET").expect("TARGET must be set"))
+    /// Writes the files generated by [`Build::prelude`] and
+    /// [`Build::constants`], and the macros shipped with this crate, to
+    /// `OUT_DIR`.
+    fn write_generated(&self) -> Result<(), String> {
+        let macros = self.generated_include_dir().join("nasm-rs");
+        let unwind = macros.join("unwind.inc");
+        std::fs::create_dir_all(macros)
+            .and_then(|_| std::fs::write(&unwind, UNWIND_INC))
+            .map_err(|e| format!("Unable to write {}: {}", unwind.display(), e))?;
+        for constants in &self.constants {
+            constants.write_to(self.generated_include_dir())?;
+        }
+        for pre_include in &self.pre_includes {
+            if let PreInclude::Prelude(text) = pre_include {
+                let path = self.pre_include_path(pre_include);
+                if let Some(parent) = path.parent() {
+                    let _ = std::fs::create_dir_all(parent);
+                }
+                std::fs::write(&path, text)
+                    .map_err(|e| format!("Unable to write {}: {}", path.display(), e))?;
+            }
+        }
+        Ok(())
     }
 
-    /// Returns version string if nasm is too old,
-    /// or error message string if it's unusable.
-    fn is_nasm_found_and_new_enough(&self, nasm_path: &Path) -> Result<(), String> {
-        let version = get_output(Command::new(nasm_path).arg("-v"))
-            .map_err(|e| format!("Unable to run {}: {}", nasm_path.display(), e))?;
-        let (major, minor, micro) = self.min_version;
-        let ver = parse_nasm_version(&version)?;
-        if major > ver.0
-            || (major == ver.0 && minor > ver.1)
-            || (major == ver.0 && minor == ver.1 && micro > ver.2)
-        {
-            Err(format!(
-                "This version of NASM is too old: {}. Required >= {}.{}.{}",
-                version, major, minor, micro
-            ))
+    /// Resolves a relative path against `CARGO_MANIFEST_DIR`, like source files.
+    fn resolve_path(&self, path: &Path) -> PathBuf {
+        if path.is_absolute() {
+            path.to_owned()
         } else {
-            Ok(())
+            self.get_src_dir().join(path)
         }
     }
 
-    fn find_nasm(&mut self) -> Result<PathBuf, String> {
-        let paths = match &self.nasm {
-            Some(p) => vec![p.to_owned()],
-            None => {
-                // Xcode has an outdated verison of nasm,
-                // and puts its own SDK first in the PATH.
-                // The proper Homebrew nasm is later in the PATH.
-                let path = env::var_os("PATH").unwrap_or_default();
-                std::iter::once(PathBuf::from("nasm"))
-                    .chain(env::split_paths(&path).map(|p| p.join("nasm")))
-                    .collect()
-            }
-        };
+    /// Returns the assembler invocations [`Build::compile_objects`] would run,
+    /// without running them.
+    ///
+    /// This locates the assembler and resolves target flags and output paths
+    /// exactly like a real build, so the result can be used for external
+    /// caching or compilation databases.
+    pub fn invocations(&self) -> Result<Vec<Invocation>, String> {
+        let target = self.get_target();
+        let nasm = self.locate_nasm().map_err(|e| e.to_string())?;
+        self.write_generated()?;
+        let args = self.get_args(&target);
 
-        let mut first_error = None;
-        for nasm_path in paths {
-            match self.is_nasm_found_and_new_enough(&nasm_path) {
-                Ok(_) => return Ok(nasm_path),
-                Err(err) => {
-                    let _ = first_error.get_or_insert(err);
-                }
-            }
-        }
-        Err(first_error.unwrap())
+        let src = &self.get_src_dir();
+        let dst = &self.get_out_dir();
+
+        Ok(self
+            .get_files(&target)
+            .iter()
+            .map(|file| self.invocation(&nasm, file, &args, src, dst))
+            .collect())
     }
-}
 
-fn parse_nasm_version(version: &str) -> Result<(usize, usize, usize), String> {
-    let mut ver = version
-        .split(' ')
-        .nth(2)
-        .ok_or_else(|| format!("Invalid nasm version '{}'", version))?;
+    /// Runs only the preprocessor on `file`, returning its output.
+    ///
+    /// The same include paths, defines and target flags as a real build are
+    /// used. The output is also written to a `.i` file next to where the
+    /// object file would be placed.
+    pub fn preprocess<P: AsRef<Path>>(&self, file: P) -> Result<String, String> {
+        let output = self.preprocess_file_list(&[file.as_ref().to_owned()])?;
+        std::fs::read_to_string(&output[0])
+            .map_err(|e| format!("Unable to read {}: {}", output[0].display(), e))
+    }
 
-    //this will probably break at some point...
-    if let Some(ver_rc) = ver.find("rc") {
-        ver = &ver[0..ver_rc];
-    }
-    let ver: Vec<_> = ver
-        .split('.')
-        .map(|v| v.parse())
-        .take_while(Result::is_ok)
-        .map(Result::unwrap)
-        .collect();
-
-    Ok((
-        ver[0],
-        ver.get(1).copied().unwrap_or(0),
-        ver.get(2).copied().unwrap_or(0),
+    /// Runs only the preprocessor on every file added to this build, returning
+    /// the paths of the `.i` files written. See [`Build::preprocess`].
+    pub fn preprocess_files(&self) -> Result<Vec<PathBuf>, String> {
+        self.preprocess_file_list(&self.get_files(&self.get_target()))
+    }
+
+    fn preprocess_file_list(&self, files: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+        let target = self.get_target();
+        let nasm = self.locate_nasm().map_err(|e| e.to_string())?;
+        self.write_generated()?;
+        let args = self.get_args(&target);
+
+        let src = &self.get_src_dir();
+        let dst = &self.get_out_dir();
+
+        files
+            .iter()
+            .map(|file| {
+                let invocation = self.preprocess_invocation(&nasm, file, &args, src, dst);
+                let dir = invocation.output.parent().unwrap();
+                std::fs::create_dir_all(dir)
+                    .map_err(|e| format!("Unable to create {}: {}", dir.display(), e))?;
+                self.run_assembler(&mut self.assembler_command(&invocation)?)?;
+                Ok(invocation.output)
+            })
+            .collect()
+    }
+
+    fn preprocess_invocation(
+        &self,
+        nasm: &Path,
+        file: &Path,
+        new_args: &[OsString],
+        src: &Path,
+        dst: &Path,
+    ) -> Invocation {
+        let output = output_path(dst, file, "i");
+        let input = src.join(file);
+
+        let mut args = new_args.to_vec();
+        args.push(self.assembler.preprocess_flag().into());
+        args.push(input.clone().into());
+        args.push("-o".into());
+        args.push(output.clone().into());
+
+        Invocation {
+            program: nasm.to_owned(),
+            args,
+            input,
+            output,
+        }
+    }
+
+    /// Like [`Build::invocations`], but returns ready-to-run commands.
+    pub fn commands(&self) -> Result<Vec<Command>, String> {
+        Ok(self
+            .invocations()?
+            .iter()
+            .map(Invocation::command)
+            .collect())
+    }
+
+    fn invocation(
+        &self,
+        nasm: &Path,
+        file: &Path,
+        new_args: &[OsString],
+        src: &Path,
+        dst: &Path,
+    ) -> Invocation {
+        let obj = output_path(dst, file, "o");
+        let input = src.join(file);
+
+        let mut args = new_args.to_vec();
+        if let Some(opt_flag) = self
+            .get_opt_level(file)
+            .and_then(|level| self.assembler.opt_level_arg(&level))
+        {
+            args.push(opt_flag.into());
+        }
+        args.push(input.clone().into());
+        args.push("-o".into());
+        args.push(obj.clone().into());
+        if self.get_track_dependencies() {
+            if let DependencyArgs::Inline(dependency_args) =
+                self.assembler.dependency_args(&obj.with_extension("d"))
+            {
+                args.extend(dependency_args);
+            }
+        }
+
+        Invocation {
+            program: nasm.to_owned(),
+            args,
+            input,
+            output: obj,
+        }
+    }
+
+    fn compile_file(
+        &self,
+        nasm: &Path,
+        file: &Path,
+        new_args: &[OsString],
+        src: &Path,
+        dst: &Path,
+    ) -> Result<PathBuf, String> {
+        let invocation = self.invocation(nasm, file, new_args, src, dst);
+        let (input, obj) = (&invocation.input, &invocation.output);
+        std::fs::create_dir_all(obj.parent().unwrap()).unwrap();
+        self.run_assembler(&mut self.assembler_command(&invocation)?)?;
+
+        let target = self.get_target();
+        if self.check_relocations && self.get_pic(&Target::resolve(&target)) {
+            check_relocations(obj)?;
+        }
+        if self.check_unwind_info {
+            check_unwind_info(obj)?;
+        }
+
+        if self.get_track_dependencies() {
+            let depfile = obj.with_extension("d");
+            let deps = match self.assembler.dependency_args(&depfile) {
+                DependencyArgs::Inline(_) => std::fs::read_to_string(&depfile)
+                    .map_err(|e| format!("Unable to read {}: {}", depfile.display(), e))?,
+                DependencyArgs::Scan(args) => get_output(
+                    Command::new(nasm)
+                        .args(new_args)
+                        .args(args)
+                        .arg(input)
+                        .arg("-o")
+                        .arg(obj),
+                )?,
+            };
+            rerun_if_changed(input);
+            for dep in parse_dependencies(&deps) {
+                rerun_if_changed(&dep);
+            }
+        }
+        Ok(obj.clone())
+    }
+
+    /// Builds the command for `invocation`, moving its arguments into a
+    /// response file next to the output if the command line would be too long.
+    fn assembler_command(&self, invocation: &Invocation) -> Result<Command, String> {
+        let len = invocation.program.as_os_str().len()
+            + invocation.args.iter().map(|a| a.len() + 1).sum::<usize>();
+        if len <= self.response_file_threshold || !self.assembler.supports_response_files() {
+            return Ok(invocation.command());
+        }
+
+        let (contents, rest) = response_file_contents(&invocation.args);
+        let mut rsp = invocation.output.clone().into_os_string();
+        rsp.push(".rsp");
+        std::fs::write(&rsp, contents)
+            .map_err(|e| format!("Unable to write {}: {}", Path::new(&rsp).display(), e))?;
+
+        let mut cmd = Command::new(&invocation.program);
+        cmd.arg("-@").arg(rsp).args(rest);
+        Ok(cmd)
+    }
+
+    /// Runs the assembler, echoing its diagnostics to stderr.
+    ///
+    /// On failure, the error lists the errors reported by the assembler.
+    fn run_assembler(&self, cmd: &mut Command) -> Result<Vec<Diagnostic>, String> {
+        let (status, diagnostics) = self.run_assembler_with_diagnostics(cmd)?;
+        if !status.success() {
+            let mut err = format!("nonzero exit status: {}", status);
+            for diagnostic in diagnostics.iter().filter(|d| d.severity >= Severity::Error) {
+                err.push('\n');
+                err.push_str(&diagnostic.to_string());
+            }
+            return Err(err);
+        }
+        Ok(diagnostics)
+    }
+
+    /// Runs the assembler, echoing its diagnostics to stderr. Only failing to
+    /// start the assembler is an error.
+    fn run_assembler_with_diagnostics(
+        &self,
+        cmd: &mut Command,
+    ) -> Result<(ExitStatus, Vec<Diagnostic>), String> {
+        info!("running: {:?}", cmd);
+
+        let out = match cmd.stdout(Stdio::inherit()).stderr(Stdio::piped()).output() {
+            Ok(out) => out,
+            Err(e) => return Err(format!("failed to spawn process: {}", e)),
+        };
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        eprint!("{}", stderr);
+
+        Ok((out.status, self.assembler.parse_diagnostics(&stderr)))
+    }
+
+    /// Assembles every file for each of `targets` without keeping any output,
+    /// collecting the diagnostics of all of them instead of stopping at the
+    /// first failure.
+    ///
+    /// Returns an error only if the assembler can't be found or run.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// let report = nasm_rs::Build::new()
+    ///     .file("src/kernel.asm")
+    ///     .check(&[
+    ///         "x86_64-unknown-linux-gnu",
+    ///         "x86_64-pc-windows-msvc",
+    ///         "x86_64-apple-darwin",
+    ///     ])
+    ///     .unwrap();
+    /// assert!(report.is_ok(), "{}", report);
+    /// ```
+    pub fn check<T: AsRef<str>>(&self, targets: &[T]) -> Result<CheckReport, String> {
+        let nasm = self.locate_nasm().map_err(|e| e.to_string())?;
+        let src = &self.get_src_dir();
+        let scratch = self.get_out_dir().join("nasm-rs-check");
+        self.write_generated()?;
+
+        let mut results = Vec::new();
+        for target in targets {
+            let target = target.as_ref();
+            let args = self.get_args(target);
+            let dst = &scratch.join(target);
+            std::fs::create_dir_all(dst)
+                .map_err(|e| format!("Unable to create {}: {}", dst.display(), e))?;
+
+            for file in self.get_files(target) {
+                let invocation = self.invocation(&nasm, &file, &args, src, dst);
+                let (status, diagnostics) =
+                    self.run_assembler_with_diagnostics(&mut self.assembler_command(&invocation)?)?;
+                results.push(CheckResult {
+                    target: target.to_owned(),
+                    file,
+                    success: status.success(),
+                    diagnostics,
+                });
+            }
+        }
+
+        let _ = std::fs::remove_dir_all(&scratch);
+        Ok(CheckReport { results })
+    }
+
+    fn archive(&self, out_dir: &Path, lib: &str, objs: &[PathBuf]) -> Result<(), String> {
+        let target = self.get_target();
+        let ar_is_msvc = self
+            .archiver_is_msvc
+            .unwrap_or_else(|| is_msvc_target(&target));
+
+        let ar = if ar_is_msvc {
+            self.find_msvc_archiver()?
+        } else {
+            self.find_archiver(&target)?
+        };
+        if ar_is_msvc {
+            let mut out_param = OsString::new();
+            out_param.push("/OUT:");
+            out_param.push(out_dir.join(lib).as_os_str());
+            run(Command::new(ar).arg(out_param).args(objs))
+        } else {
+            run(Command::new(ar)
+                .arg("crus")
+                .arg(out_dir.join(lib))
+                .args(objs))
+        }
+    }
+
+    /// Looks up a `lib.exe`-style archiver. Outside of Windows hosts the MSVC
+    /// toolchain isn't available, so `llvm-lib` is used instead.
+    fn find_msvc_archiver(&self) -> Result<PathBuf, String> {
+        if let Some(ar) = &self.archiver {
+            return Ok(ar.clone());
+        }
+        if cfg!(windows) {
+            return Ok("lib".into());
+        }
+        find_in_path("llvm-lib").ok_or_else(|| {
+            "Unable to find an MSVC-style archiver: llvm-lib was not found in PATH. \
+             Install LLVM or call Build::archiver() to choose one explicitly."
+                .to_owned()
+        })
+    }
+
+    /// Looks up a `ar`-style archiver the same way `cc` does: `AR_<target>`,
+    /// `AR_<target_with_underscores>`, `TARGET_AR` or `HOST_AR`, `AR`, and
+    /// finally `<prefix>-ar`, `llvm-ar` and `ar` on the `PATH`.
+    fn find_archiver(&self, target: &str) -> Result<PathBuf, String> {
+        if let Some(ar) = &self.archiver {
+            return Ok(ar.clone());
+        }
+        find_binutil("AR", "ar", target).map_err(|tried| {
+            format!(
+                "Unable to find an archiver for target {}. {} \
+                 Set AR_{} or call Build::archiver() to choose one explicitly.",
+                target, tried, target,
+            )
+        })
+    }
+
+    fn get_out_dir(&self) -> PathBuf {
+        self.out_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR must be set")))
+    }
+
+    fn get_track_dependencies(&self) -> bool {
+        self.track_dependencies
+            .unwrap_or(matches!(self.assembler, Assembler::Nasm))
+    }
+
+    fn get_pic(&self, target: &Target) -> bool {
+        self.pic.unwrap_or_else(|| {
+            let relocation_model = env::var("CARGO_ENCODED_RUSTFLAGS").ok().and_then(|flags| {
+                codegen_option_from_rustflags(&flags, "relocation-model").map(str::to_owned)
+            });
+            match relocation_model.as_deref() {
+                Some("static") | Some("dynamic-no-pic") => false,
+                Some(_) => true,
+                None => HOSTED_OSES.contains(&target.os.as_str()),
+            }
+        })
+    }
+
+    fn get_opt_level(&self, file: &Path) -> Option<String> {
+        self.file_opt_levels
+            .get(file)
+            .or(self.opt_level.as_ref())
+            .cloned()
+            .or_else(|| env::var("OPT_LEVEL").ok())
+    }
+
+    /// The files to compile for `target`, in the order they were added.
+    fn get_files(&self, target: &str) -> Vec<PathBuf> {
+        let parsed = Target::resolve(target);
+        self.files
+            .iter()
+            .filter(|file| match &file.condition {
+                Some(matches) => matches(&parsed),
+                None => true,
+            })
+            .map(|file| file.path.clone())
+            .collect()
+    }
+
+    fn get_src_dir(&self) -> PathBuf {
+        PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR must be set"))
+    }
+
+    fn get_target(&self) -> String {
+        self.target
+            .clone()
+            .unwrap_or_else(|| env::var("TARGET").expect("TARGET must be set"))
+    }
+
+    /// Checks whether `nasm_path` can be run and satisfies `min_version` and `max_version`.
+    fn check_nasm(&self, nasm_path: &Path) -> Result<NasmVersion, NasmRejection> {
+        let out = match Command::new(nasm_path)
+            .args(self.assembler.version_args())
+            .output()
+        {
+            Ok(out) => out,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(NasmRejection::NotFound)
+            }
+            Err(e) => return Err(NasmRejection::FailedToRun(e.to_string())),
+        };
+        if !out.status.success() {
+            return Err(NasmRejection::FailedToRun(
+                String::from_utf8_lossy(&out.stderr).trim().to_owned(),
+            ));
+        }
+        let version = String::from_utf8_lossy(&out.stdout).trim().to_owned();
+        let ver = self
+            .assembler
+            .parse_version(&version)
+            .map_err(NasmRejection::FailedToRun)?;
+        let triple = (ver.major, ver.minor, ver.micro);
+
+        let (major, minor, micro) = self.min_version;
+        if triple < self.min_version {
+            return Err(NasmRejection::TooOld(format!(
+                "{}. Required >= {}.{}.{}",
+                version, major, minor, micro
+            )));
+        }
+        if let Some((major, minor, micro)) = self.max_version.filter(|max| triple > *max) {
+            return Err(NasmRejection::TooNew(format!(
+                "{}. Required <= {}.{}.{}",
+                version, major, minor, micro
+            )));
+        }
+        Ok(ver)
+    }
+
+    fn nasm_candidates(&self) -> Vec<PathBuf> {
+        // Looking for tools prints `rerun-if-env-changed`, which stops Cargo
+        // from rerunning the build script when package files change.
+        self.rerun_if_inputs_changed();
+        if let Some(nasm) = &self.nasm {
+            return vec![nasm.clone()];
+        }
+        let target = self.get_target();
+        let env_var = self.assembler.env_var();
+        if let Some(nasm) = getenv_with_target_prefixes(env_var, &target, &mut Vec::new()) {
+            return vec![nasm.into()];
+        }
+
+        let program = self.assembler.name();
+        let nasm = format!("{}{}", program, env::consts::EXE_SUFFIX);
+        let mut candidates = Vec::new();
+
+        rerun_if_env_changed("NASM_RS_SEARCH_PATH");
+        if let Some(search_path) = env::var_os("NASM_RS_SEARCH_PATH") {
+            candidates.extend(env::split_paths(&search_path).map(|p| p.join(&nasm)));
+        }
+
+        // Xcode has an outdated verison of nasm,
+        // and puts its own SDK first in the PATH.
+        // The proper Homebrew nasm is later in the PATH.
+        candidates.push(PathBuf::from(program));
+        let path = env::var_os("PATH").unwrap_or_default();
+        candidates.extend(
+            env::split_paths(&path)
+                .map(|p| p.join(&nasm))
+                .filter(|p| p.is_file()),
+        );
+        candidates
+    }
+
+    /// Prints `rerun-if-changed` for every source, include directory and
+    /// pre-included file.
+    fn rerun_if_inputs_changed(&self) {
+        for file in &self.files {
+            rerun_if_changed(&self.resolve_path(&file.path));
+        }
+        for dir in &self.includes {
+            rerun_if_changed(&self.resolve_path(dir));
+        }
+        for pre_include in &self.pre_includes {
+            if let PreInclude::File(path) = pre_include {
+                rerun_if_changed(&self.resolve_path(path));
+            }
+        }
+    }
+
+    /// Finds a NASM executable satisfying `min_version` and `max_version`.
+    ///
+    /// On failure, the returned error lists every candidate that was tried
+    /// along with the reason it was rejected.
+    pub fn locate_nasm(&self) -> Result<PathBuf, NasmNotFound> {
+        self.locate_nasm_with_version().map(|(path, _)| path)
+    }
+
+    fn locate_nasm_with_version(&self) -> Result<(PathBuf, NasmVersion), NasmNotFound> {
+        let mut rejected = Vec::new();
+        for nasm_path in self.nasm_candidates() {
+            match self.check_nasm(&nasm_path) {
+                Ok(version) => return Ok((nasm_path, version)),
+                Err(rejection) => rejected.push(NasmCandidate {
+                    path: nasm_path,
+                    rejection,
+                }),
+            }
+        }
+        Err(NasmNotFound {
+            assembler: self.assembler,
+            candidates: rejected,
+        })
+    }
+
+    /// Locates the assembler. If it's unusable and `graceful` is set, reports
+    /// that to Cargo and returns `Ok(None)` instead of an error.
+    fn find_nasm(&self, graceful: bool) -> Result<Option<PathBuf>, String> {
+        if graceful {
+            println!("cargo:rustc-check-cfg=cfg({})", self.unavailable_cfg);
+        }
+        match self.locate_nasm() {
+            Ok(nasm) => Ok(Some(nasm)),
+            Err(err) if graceful => {
+                eprintln!("{}", err);
+                println!(
+                    "cargo:warning={} is unavailable, building without assembly (cfg {}): {}",
+                    self.assembler,
+                    self.unavailable_cfg,
+                    err.candidates
+                        .iter()
+                        .map(|c| format!("{}: {}", c.path.display(), c.rejection))
+                        .collect::<Vec<_>>()
+                        .join("; ")
+                );
+                println!("cargo:rustc-cfg={}", self.unavailable_cfg);
+                Ok(None)
+            }
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+/// Why a NASM executable considered by [`Build::locate_nasm`] was not used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NasmRejection {
+    /// The executable does not exist.
+    NotFound,
+    /// The executable is older than the required minimum version.
+    TooOld(String),
+    /// The executable is newer than the maximum version accepted.
+    TooNew(String),
+    /// The executable could not be run, or its version was not understood.
+    FailedToRun(String),
+}
+
+/// A NASM executable considered by [`Build::locate_nasm`] and rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NasmCandidate {
+    pub path: PathBuf,
+    pub rejection: NasmRejection,
+}
+
+/// Returned by [`Build::locate_nasm`] when no usable NASM was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NasmNotFound {
+    pub assembler: Assembler,
+    pub candidates: Vec<NasmCandidate>,
+}
+
+impl fmt::Display for NasmRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NasmRejection::NotFound => f.write_str("not found"),
+            NasmRejection::TooOld(version) => write!(f, "too old: {}", version),
+            NasmRejection::TooNew(version) => write!(f, "too new: {}", version),
+            NasmRejection::FailedToRun(err) => write!(f, "unable to run: {}", err),
+        }
+    }
+}
+
+impl fmt::Display for NasmNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unable to find a usable {}. Tried:", self.assembler)?;
+        for candidate in &self.candidates {
+            write!(
+                f,
+                "\n  {}: {}",
+                candidate.path.display(),
+                candidate.rejection
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NasmNotFound {}
+
+/// Version of an assembler, e.g. `2.16.01` or `2.16rc2`.
+///
+/// Release candidates order before the release they precede. Versions are
+/// displayed the way the assembler spells them, e.g. `2.14.02` for NASM and
+/// `1.3.0` for YASM.
+#[derive(Debug, Clone, Copy)]
+pub struct NasmVersion {
+    pub major: usize,
+    pub minor: usize,
+    pub micro: usize,
+    pub patchlevel: usize,
+    /// Release candidate number, if this is not a final release.
+    pub rc: Option<usize>,
+    /// Whether the numbers after the major version are displayed with two
+    /// digits, as NASM does.
+    zero_padded: bool,
+}
+
+impl NasmVersion {
+    pub fn new(major: usize, minor: usize, micro: usize) -> Self {
+        Self {
+            major,
+            minor,
+            micro,
+            patchlevel: 0,
+            rc: None,
+            zero_padded: true,
+        }
+    }
+
+    /// The same version, displayed without zero padding like YASM's.
+    pub(crate) fn unpadded(self) -> Self {
+        Self {
+            zero_padded: false,
+            ..self
+        }
+    }
+}
+
+impl PartialEq for NasmVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for NasmVersion {}
+
+impl Hash for NasmVersion {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.major, self.minor, self.micro, self.patchlevel, self.rc).hash(state);
+    }
+}
+
+impl Ord for NasmVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let numbers = |v: &Self| (v.major, v.minor, v.micro, v.patchlevel);
+        numbers(self)
+            .cmp(&numbers(other))
+            .then_with(|| match (self.rc, other.rc) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(_), None) => Ordering::Less,
+                (Some(a), Some(b)) => a.cmp(&b),
+            })
+    }
+}
+
+impl PartialOrd for NasmVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for NasmVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = if self.zero_padded { 2 } else { 1 };
+        write!(f, "{}.{:02$}", self.major, self.minor, width)?;
+        if self.micro != 0 || self.patchlevel != 0 || !self.zero_padded {
+            write!(f, ".{:01$}", self.micro, width)?;
+        }
+        if self.patchlevel != 0 {
+            write!(f, ".{:01$}", self.patchlevel, width)?;
+        }
+        if let Some(rc) = self.rc {
+            write!(f, "rc{}", rc)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for NasmVersion {
+    type Err = String;
+
+    /// Parses a bare version number such as `2.14.02` or `2.16rc2`.
+    fn from_str(ver: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid version number '{}'", ver);
+        let leading_number = |s: &str| -> Option<usize> {
+            let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+            s[..end].parse().ok()
+        };
+
+        let (numbers, rc) = match ver.find("rc") {
+            Some(i) => (&ver[..i], Some(leading_number(&ver[i + 2..]).unwrap_or(0))),
+            None => (ver, None),
+        };
+        let mut parts = numbers.split('.').map(leading_number);
+        let major = parts.next().flatten().ok_or_else(invalid)?;
+        let mut next = || parts.next().flatten().unwrap_or(0);
+        Ok(NasmVersion {
+            major,
+            minor: next(),
+            micro: next(),
+            patchlevel: next(),
+            rc,
+            zero_padded: true,
+        })
+    }
+}
+
+/// A single assembler run, as performed by [`Build::compile_objects`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    /// The assembler executable.
+    pub program: PathBuf,
+    /// All arguments, including the input and output paths.
+    pub args: Vec<OsString>,
+    /// The source file being assembled.
+    pub input: PathBuf,
+    /// The object file produced.
+    pub output: PathBuf,
+}
+
+impl Invocation {
+    /// Builds a [`Command`] which performs this invocation.
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(&self.args);
+        cmd
+    }
+}
+
+/// Splits `args` into the contents of a NASM response file and the
+/// arguments which must stay on the command line.
+///
+/// NASM reads each line of a response file as one argument, with surrounding
+/// whitespace trimmed, so no quoting is needed. Arguments which can't survive
+/// that, such as those containing newlines, are returned separately.
+fn response_file_contents(args: &[OsString]) -> (Vec<u8>, Vec<OsString>) {
+    let mut contents = Vec::new();
+    let mut rest = Vec::new();
+    for arg in args {
+        let bytes = os_str_bytes(arg);
+        let representable = !bytes.is_empty()
+            && !bytes.contains(&b'\n')
+            && !bytes.contains(&b'\r')
+            && !bytes.first().unwrap().is_ascii_whitespace()
+            && !bytes.last().unwrap().is_ascii_whitespace();
+        if representable {
+            contents.extend_from_slice(&bytes);
+            contents.push(b'\n');
+        } else {
+            rest.push(arg.clone());
+        }
+    }
+    (contents, rest)
+}
+
+#[cfg(unix)]
+fn os_str_bytes(s: &OsStr) -> std::borrow::Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    std::borrow::Cow::Borrowed(s.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn os_str_bytes(s: &OsStr) -> std::borrow::Cow<'_, [u8]> {
+    match s.to_string_lossy() {
+        std::borrow::Cow::Borrowed(s) => std::borrow::Cow::Borrowed(s.as_bytes()),
+        std::borrow::Cow::Owned(s) => std::borrow::Cow::Owned(s.into_bytes()),
+    }
+}
+
+/// Names the output for source `file` after its stem and a hash of its path,
+/// so that sources like `a.asm`, `a.s` and `x86_64/a.asm` don't overwrite
+/// each other's output.
+fn output_path(dst: &Path, file: &Path, extension: &str) -> PathBuf {
+    // FNV-1a, which unlike `DefaultHasher` is stable across Rust versions.
+    let hash = os_str_bytes(file.as_os_str())
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    let mut name = file.file_stem().unwrap_or_default().to_owned();
+    name.push(format!("-{:016x}.{}", hash, extension));
+    dst.join(name)
+}
+
+/// Formats an `-I` argument for `dir`, without going through `str` so that
+/// non-UTF-8 paths survive.
+fn include_arg(dir: &Path) -> OsString {
+    let mut arg = OsString::from("-I");
+    arg.push(dir.as_os_str());
+    // nasm requires trailing slash, but `Path` may omit it.
+    let has_separator = dir
+        .to_string_lossy()
+        .ends_with(|c: char| std::path::is_separator(c));
+    if !has_separator {
+        arg.push(std::path::MAIN_SEPARATOR.to_string());
+    }
+    arg
+}
+
+/// Formats invocations as a JSON compilation database.
+fn compile_commands_json(invocations: &[Invocation], directory: &Path) -> String {
+    let directory = json_string(&directory.to_string_lossy());
+    let entries = invocations
+        .iter()
+        .map(|invocation| {
+            let arguments = std::iter::once(invocation.program.as_os_str())
+                .chain(invocation.args.iter().map(OsString::as_os_str))
+                .map(|arg| json_string(&arg.to_string_lossy()))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!(
+                "  {{\n    \"directory\": {},\n    \"arguments\": [{}],\n    \"file\": {},\n    \"output\": {}\n  }}",
+                directory,
+                arguments,
+                json_string(&invocation.input.to_string_lossy()),
+                json_string(&invocation.output.to_string_lossy()),
+            )
+        })
+        .collect::<Vec<_>>();
+    format!("[\n{}\n]\n", entries.join(",\n"))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Outcome of assembling one file for one target in [`Build::check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    pub target: String,
+    pub file: PathBuf,
+    /// Whether the assembler succeeded.
+    pub success: bool,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Every result collected by [`Build::check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckReport {
+    pub results: Vec<CheckResult>,
+}
+
+impl CheckReport {
+    /// Whether every file assembled for every target.
+    pub fn is_ok(&self) -> bool {
+        self.results.iter().all(|r| r.success)
+    }
+
+    /// The results of files which failed to assemble.
+    pub fn failures(&self) -> impl Iterator<Item = &CheckResult> {
+        self.results.iter().filter(|r| !r.success)
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let failed = self.failures().count();
+        write!(f, "{} of {} checks failed", failed, self.results.len())?;
+        for result in &self.results {
+            if result.success && result.diagnostics.is_empty() {
+                continue;
+            }
+            let status = if result.success { "ok" } else { "FAILED" };
+            write!(
+                f,
+                "\n[{}] {}: {}",
+                result.target,
+                result.file.display(),
+                status
+            )?;
+            for diagnostic in &result.diagnostics {
+                write!(f, "\n  {}", diagnostic)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Instruction set extensions which can be probed with [`Build::nasm_supports`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum InstructionSet {
+    Avx,
+    Avx2,
+    Avx512,
+    Avx512Fp16,
+    AvxVnni,
+    Vaes,
+    Gfni,
+    Sha,
+    Amx,
+    Apx,
+}
+
+impl InstructionSet {
+    /// An instruction only assemblers supporting this extension accept.
+    pub fn probe_snippet(&self) -> &'static str {
+        match self {
+            InstructionSet::Avx => "vaddps ymm0, ymm1, ymm2",
+            InstructionSet::Avx2 => "vpbroadcastd ymm0, xmm1",
+            InstructionSet::Avx512 => "vpaddd zmm0{k1}, zmm1, zmm2",
+            InstructionSet::Avx512Fp16 => "vaddph zmm0, zmm1, zmm2",
+            InstructionSet::AvxVnni => "{vex} vpdpbusd ymm0, ymm1, ymm2",
+            InstructionSet::Vaes => "vaesenc ymm0, ymm1, ymm2",
+            InstructionSet::Gfni => "gf2p8affineqb xmm0, xmm1, 0",
+            InstructionSet::Sha => "sha256rnds2 xmm0, xmm1",
+            InstructionSet::Amx => "tilezero tmm0",
+            InstructionSet::Apx => "add r16, r17",
+        }
+    }
+}
+
+/// Extracts the version from `nasm -v` output, e.g.
+/// `NASM version 2.14.02 compiled on Jan 22 2019`.
+fn parse_nasm_version(version: &str) -> Result<NasmVersion, String> {
+    let words = version.split_whitespace().collect::<Vec<_>>();
+    // Vendor-patched builds may add words to the banner, so look for the
+    // number following "version", or failing that the first number.
+    let ver = words
+        .iter()
+        .position(|w| w.eq_ignore_ascii_case("version"))
+        .and_then(|i| words.get(i + 1))
+        .filter(|w| w.starts_with(|c: char| c.is_ascii_digit()))
+        .or_else(|| {
+            words
+                .iter()
+                .find(|w| w.starts_with(|c: char| c.is_ascii_digit()))
+        })
+        .ok_or_else(|| format!("Invalid nasm version '{}'", version))?;
+
+    ver.parse()
+}
+
+/// Reads a tool override from the environment, trying `<var>_<target>`,
+/// `<var>_<target_with_underscores>`, `TARGET_<var>` (or `HOST_<var>` when not
+/// cross-compiling) and plain `<var>`, in that order.
+///
+/// Every variable consulted is recorded in `tried` and reported to Cargo with
+/// `rerun-if-env-changed`.
+fn getenv_with_target_prefixes(
+    var: &str,
+    target: &str,
+    tried: &mut Vec<String>,
+) -> Option<OsString> {
+    let kind = if is_cross_compiling(target) {
+        "TARGET"
+    } else {
+        "HOST"
+    };
+    let names = [
+        format!("{}_{}", var, target),
+        format!("{}_{}", var, target.replace('-', "_")),
+        format!("{}_{}", kind, var),
+        var.to_owned(),
+    ];
+
+    for name in names {
+        if tried.contains(&name) {
+            continue;
+        }
+        rerun_if_env_changed(&name);
+        let value = env::var_os(&name);
+        tried.push(name);
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Prints `cargo:rerun-if-env-changed` for `name`, unless it was already.
+fn rerun_if_env_changed(name: &str) {
+    let _ = print_rerun_line(format!("cargo:rerun-if-env-changed={}", name));
+}
+
+/// Prints `cargo:rerun-if-changed` for `path`, unless it was already.
+fn rerun_if_changed(path: &Path) {
+    let _ = print_rerun_line(format!("cargo:rerun-if-changed={}", path.display()));
+}
+
+/// Prints `line` and returns `true` if it wasn't printed before.
+fn print_rerun_line(line: String) -> bool {
+    let mut printed = RERUN_LINES.lock().unwrap_or_else(|e| e.into_inner());
+    if printed.contains(&line) {
+        return false;
+    }
+    println!("{}", line);
+    printed.insert(line)
+}
+
+/// Whether `target` uses the MSVC environment. Build scripts are compiled for
+/// the host, so `cfg!(target_env)` can't be used to answer this.
+fn is_msvc_target(target: &str) -> bool {
+    Target::resolve(target).env == "msvc"
+}
+
+/// Looks up a binutils-style tool the same way `cc` does: `<VAR>_<target>`,
+/// `<VAR>_<target_with_underscores>`, `TARGET_<VAR>` or `HOST_<VAR>`, `<VAR>`,
+/// and finally `<prefix>-<tool>`, `llvm-<tool>` and `<tool>` on the `PATH`.
+///
+/// On failure, returns a description of everything that was tried.
+fn find_binutil(var: &str, tool: &str, target: &str) -> Result<PathBuf, String> {
+    let mut tried_vars = Vec::new();
+    if let Some(path) = getenv_with_target_prefixes(var, target, &mut tried_vars) {
+        return Ok(path.into());
+    }
+
+    let mut programs = Vec::new();
+    if is_cross_compiling(target) {
+        for prefix in cross_tool_prefixes(target) {
+            programs.push(format!("{}-{}", prefix, tool));
+        }
+        programs.push(format!("llvm-{}", tool));
+        programs.push(tool.to_owned());
+    } else {
+        programs.push(tool.to_owned());
+        programs.push(format!("llvm-{}", tool));
+    }
+
+    for program in &programs {
+        if let Some(path) = find_in_path(program) {
+            return Ok(path);
+        }
+    }
+
+    Err(format!(
+        "Tried environment variables {} and programs {} in PATH.",
+        tried_vars.join(", "),
+        programs.join(", "),
     ))
 }
 
+/// How much debug information to generate, following Cargo's `debug` setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DebugInfo {
+    /// `debug = 0`, `false` or `"none"`.
+    None,
+    /// `debug = "line-tables-only"` or `"line-directives-only"`.
+    LineTablesOnly,
+    /// `debug = 1` or `"limited"`.
+    Limited,
+    /// `debug = 2`, `true` or `"full"`.
+    Full,
+}
+
+impl FromStr for DebugInfo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "false" | "0" | "none" => Ok(DebugInfo::None),
+            "line-tables-only" | "line-directives-only" => Ok(DebugInfo::LineTablesOnly),
+            "1" | "limited" => Ok(DebugInfo::Limited),
+            "true" | "2" | "full" => Ok(DebugInfo::Full),
+            _ => Err(format!("Unknown debug info level '{}'", s)),
+        }
+    }
+}
+
+fn debug_info_from_env() -> DebugInfo {
+    match env::var("DEBUG").ok() {
+        // Unknown values were always treated as enabled.
+        Some(debug) => debug.parse().unwrap_or(DebugInfo::Full),
+        None => DebugInfo::None,
+    }
+}
+
+/// Finds the value of the last `-C <name>=...` in `CARGO_ENCODED_RUSTFLAGS`,
+/// whose flags are separated by `0x1f`.
+fn codegen_option_from_rustflags<'a>(flags: &'a str, name: &str) -> Option<&'a str> {
+    let mut value = None;
+    let mut flags = flags.split('\x1f');
+    while let Some(flag) = flags.next() {
+        let codegen = match flag {
+            "-C" | "--codegen" => flags.next(),
+            _ => flag
+                .strip_prefix("-C")
+                .or_else(|| flag.strip_prefix("--codegen=")),
+        };
+        if let Some(v) = codegen
+            .and_then(|c| c.strip_prefix(name))
+            .and_then(|c| c.strip_prefix('='))
+        {
+            value = Some(v);
+        }
+    }
+    value
+}
+
+/// Fails if `obj` has relocations that can't be used in position independent code.
+fn check_relocations(obj: &Path) -> Result<(), String> {
+    let data =
+        std::fs::read(obj).map_err(|e| format!("Unable to read {}: {}", obj.display(), e))?;
+    let relocs = elf::absolute_relocations(&data)
+        .map_err(|e| format!("Unable to read {}: {}", obj.display(), e))?;
+    if relocs.is_empty() {
+        return Ok(());
+    }
+    let mut msg = format!(
+        "{} has absolute relocations, which can't be used in position independent code:",
+        obj.display()
+    );
+    for reloc in relocs {
+        msg.push_str(&format!("\n  {}", reloc));
+    }
+    msg.push_str("\nUse RIP-relative addressing, or call Build::pic(false) if this is intended.");
+    Err(msg)
+}
+
+/// Warns about exported functions in `obj` without unwind information.
+fn check_unwind_info(obj: &Path) -> Result<(), String> {
+    let data =
+        std::fs::read(obj).map_err(|e| format!("Unable to read {}: {}", obj.display(), e))?;
+    let functions = coff::functions_without_unwind_info(&data)
+        .map_err(|e| format!("Unable to read {}: {}", obj.display(), e))?;
+    for function in functions {
+        println!(
+            "cargo:warning={}: `{}` has no unwind information (.pdata entry)",
+            obj.display(),
+            function
+        );
+    }
+    Ok(())
+}
+
+/// Macros for Windows unwind information, written to `nasm-rs/unwind.inc` in
+/// the generated include directory.
+const UNWIND_INC: &str = include_str!("../include/nasm-rs/unwind.inc");
+
+fn warnings_into_errors_from_env() -> bool {
+    rerun_if_env_changed("NASM_RS_WERROR");
+    let werror = env::var("NASM_RS_WERROR")
+        .is_ok_and(|v| !matches!(v.as_str(), "" | "0" | "false" | "no" | "off"));
+    werror || env::var_os("CARGO_CFG_NASM_RS_WERROR").is_some()
+}
+
+fn is_cross_compiling(target: &str) -> bool {
+    env::var("HOST").is_ok_and(|host| host != target)
+}
+
+/// Prefixes under which cross toolchains for `target` are usually installed,
+/// e.g. `x86_64-w64-mingw32` for `x86_64-pc-windows-gnu`.
+fn cross_tool_prefixes(target: &str) -> Vec<String> {
+    let parts = target.split('-').collect::<Vec<_>>();
+    let mut prefixes = Vec::new();
+
+    if parts.len() >= 4 {
+        let (arch, os, environ) = (parts[0], parts[2], parts[3]);
+        match (os, environ) {
+            ("windows", "gnu") | ("windows", "gnullvm") => {
+                prefixes.push(format!("{}-w64-mingw32", arch))
+            }
+            ("linux", environ) => prefixes.push(format!("{}-linux-{}", arch, environ)),
+            _ => {}
+        }
+    }
+    prefixes.push(target.to_owned());
+    prefixes
+}
+
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    let program = format!("{}{}", program, env::consts::EXE_SUFFIX);
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(&program))
+        .find(|candidate| candidate.is_file())
+}
+
 fn get_output(cmd: &mut Command) -> Result<String, String> {
+    info!("running: {:?}", cmd);
     let out = cmd.output().map_err(|e| e.to_string())?;
     if out.status.success() {
         Ok(String::from_utf8_lossy(&out.stdout).to_string())
@@ -589,32 +2327,731 @@ fn test_build() {
     build.target("i686-unknown-linux-musl");
     build.out_dir("/tmp");
     build.min_version(0, 0, 0);
+    build.warnings_into_errors(false);
 
+    let manifest_dir = env!("CARGO_MANIFEST_DIR");
     assert_eq!(
         build.get_args("i686-unknown-linux-musl"),
-        &["-felf32", "-I./", "-Idir/", "-Dfoo=1", "-Dbar", "-test"]
+        &[
+            "-felf32",
+            "-DPIC",
+            &format!("-I{}/./", manifest_dir),
+            &format!("-I{}/dir/", manifest_dir),
+            "-I/tmp/nasm-rs-include/",
+            "-Dfoo=1",
+            "-Dbar",
+            "-test"
+        ]
     );
 }
 
 #[test]
 fn test_parse_nasm_version() {
     let ver_str = "NASM version 2.14.02 compiled on Jan 22 2019";
-    assert_eq!((2, 14, 2), parse_nasm_version(ver_str).unwrap());
+    assert_eq!(
+        NasmVersion::new(2, 14, 2),
+        parse_nasm_version(ver_str).unwrap()
+    );
     let ver_str = "NASM version 2.14.02";
-    assert_eq!((2, 14, 2), parse_nasm_version(ver_str).unwrap());
+    assert_eq!(
+        NasmVersion::new(2, 14, 2),
+        parse_nasm_version(ver_str).unwrap()
+    );
     let ver_str = "NASM version 2.14 compiled on Jan 22 2019";
-    assert_eq!((2, 14, 0), parse_nasm_version(ver_str).unwrap());
+    assert_eq!(
+        NasmVersion::new(2, 14, 0),
+        parse_nasm_version(ver_str).unwrap()
+    );
     let ver_str = "NASM version 2.14";
-    assert_eq!((2, 14, 0), parse_nasm_version(ver_str).unwrap());
+    assert_eq!(
+        NasmVersion::new(2, 14, 0),
+        parse_nasm_version(ver_str).unwrap()
+    );
     let ver_str = "NASM version 2.14rc2";
-    assert_eq!((2, 14, 0), parse_nasm_version(ver_str).unwrap());
+    let ver = parse_nasm_version(ver_str).unwrap();
+    assert_eq!(
+        (2, 14, 0, Some(2)),
+        (ver.major, ver.minor, ver.micro, ver.rc)
+    );
+    let ver_str = "NASM (Vendor build 7) version 2.15.05.01";
+    let ver = parse_nasm_version(ver_str).unwrap();
+    assert_eq!(
+        (2, 15, 5, 1),
+        (ver.major, ver.minor, ver.micro, ver.patchlevel)
+    );
+    assert!(parse_nasm_version("NASM version").is_err());
+    assert!(parse_nasm_version("").is_err());
+}
+
+#[test]
+fn test_nasm_version_ord() {
+    let rc: NasmVersion = "2.16rc2".parse().unwrap();
+    let release: NasmVersion = "2.16".parse().unwrap();
+    let patch: NasmVersion = "2.16.01".parse().unwrap();
+    assert!("2.16rc1".parse::<NasmVersion>().unwrap() < rc);
+    assert!(rc < release);
+    assert!(release < patch);
+    assert_eq!(rc.to_string(), "2.16rc2");
+    assert_eq!(patch.to_string(), "2.16.01");
+}
+
+#[test]
+fn test_object_format() {
+    let target = Target::parse("x86_64-unknown-linux-gnux32");
+    assert_eq!(object_format(&target), ("-felfx32", "-gdwarf"));
+
+    let target = Target::parse("x86_64-unknown-linux");
+    assert_eq!(object_format(&target), ("-felf64", "-gdwarf"));
+
+    let target = Target::parse("i686-linux-android");
+    assert_eq!(object_format(&target), ("-felf32", "-gdwarf"));
+
+    // A custom target spec described by `CARGO_CFG_TARGET_*`.
+    let mut target = Target::parse("x86_64-ourkernel");
+    assert_eq!(object_format(&target), ("", "-g"));
+    target.os = "none".into();
+    assert_eq!(object_format(&target), ("-felf64", "-gdwarf"));
+
+    // Cargo describes Apple targets with `target_os = "macos"`.
+    let target = Target::with_cfg("x86_64-apple-darwin", |key| match key {
+        "CARGO_CFG_TARGET_ARCH" => Some("x86_64".into()),
+        "CARGO_CFG_TARGET_VENDOR" => Some("apple".into()),
+        "CARGO_CFG_TARGET_OS" => Some("macos".into()),
+        "CARGO_CFG_TARGET_ENV" => Some("".into()),
+        "CARGO_CFG_TARGET_POINTER_WIDTH" => Some("64".into()),
+        _ => None,
+    });
+    assert_eq!(target, Target::parse("x86_64-apple-darwin"));
+    assert_eq!(object_format(&target), ("-fmacho64", "-gdwarf"));
+}
+
+#[test]
+fn test_cross_tool_prefixes() {
+    assert_eq!(
+        cross_tool_prefixes("x86_64-pc-windows-gnu"),
+        &["x86_64-w64-mingw32", "x86_64-pc-windows-gnu"]
+    );
+    assert_eq!(
+        cross_tool_prefixes("i686-unknown-linux-musl"),
+        &["i686-linux-musl", "i686-unknown-linux-musl"]
+    );
+    assert_eq!(
+        cross_tool_prefixes("x86_64-unknown-freebsd"),
+        &["x86_64-unknown-freebsd"]
+    );
+}
+
+#[test]
+fn test_is_msvc_target() {
+    assert!(is_msvc_target("x86_64-pc-windows-msvc"));
+    assert!(is_msvc_target("i686-pc-windows-msvc"));
+    assert!(!is_msvc_target("x86_64-pc-windows-gnu"));
+    assert!(!is_msvc_target("x86_64-unknown-linux-gnu"));
+}
+
+#[test]
+fn test_nasm_not_found_report() {
+    let err = NasmNotFound {
+        assembler: Assembler::Nasm,
+        candidates: vec![
+            NasmCandidate {
+                path: PathBuf::from("nasm"),
+                rejection: NasmRejection::NotFound,
+            },
+            NasmCandidate {
+                path: PathBuf::from("/opt/nasm/bin/nasm"),
+                rejection: NasmRejection::TooOld("NASM version 2.10. Required >= 2.14.0".into()),
+            },
+        ],
+    };
+    assert_eq!(
+        err.to_string(),
+        "Unable to find a usable nasm. Tried:\n  nasm: not found\n  \
+         /opt/nasm/bin/nasm: too old: NASM version 2.10. Required >= 2.14.0"
+    );
+}
+
+#[test]
+fn test_probe_without_nasm() {
+    let mut build = Build::new();
+    build.nasm("/nonexistent/nasm");
+    build.target("x86_64-unknown-linux-gnu");
+    build.out_dir(env::temp_dir());
+    assert!(!build.probe_instruction("nop"));
+    assert!(!build.nasm_supports(InstructionSet::Avx512));
+}
+
+#[test]
+fn test_try_compile_without_nasm() {
+    let mut build = Build::new();
+    build.file("test");
+    build.nasm("/nonexistent/nasm");
+    build.target("x86_64-unknown-linux-gnu");
+    build.out_dir(env::temp_dir());
+    assert_eq!(build.try_compile_objects(), Ok(None));
+    assert_eq!(build.try_compile("test"), Ok(None));
+    assert!(build.compile_objects().is_err());
+    build.optional(true);
+    assert_eq!(build.compile_objects(), Ok(Vec::new()));
+}
+
+#[test]
+fn test_invocations() {
+    let mut build = Build::new();
+    build.warnings_into_errors(false);
+    build.file("src/foo.asm");
+    build.nasm("/nonexistent/nasm");
+    build.target("x86_64-unknown-linux-gnu");
+    build.out_dir("/tmp/out");
+    build.define("FOO", None);
+    build.pic(false);
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+
+    let invocation = build.invocation(
+        Path::new("/nonexistent/nasm"),
+        Path::new("src/foo.asm"),
+        &build.get_args("x86_64-unknown-linux-gnu"),
+        &manifest_dir,
+        Path::new("/tmp/out"),
+    );
+    assert_eq!(invocation.input, manifest_dir.join("src/foo.asm"));
+    assert_eq!(
+        invocation.output,
+        PathBuf::from("/tmp/out/foo-afe61fbdb5de5f3b.o")
+    );
+    assert_eq!(
+        invocation.args,
+        vec![
+            OsString::from("-felf64"),
+            include_arg(&build.generated_include_dir()),
+            OsString::from("-DFOO"),
+            manifest_dir.join("src/foo.asm").into(),
+            OsString::from("-o"),
+            OsString::from("/tmp/out/foo-afe61fbdb5de5f3b.o"),
+            OsString::from("-MD"),
+            OsString::from("/tmp/out/foo-afe61fbdb5de5f3b.d"),
+        ]
+    );
+
+    build.track_dependencies(false);
+    let invocation = build.invocation(
+        Path::new("/nonexistent/nasm"),
+        Path::new("src/foo.asm"),
+        &build.get_args("x86_64-unknown-linux-gnu"),
+        &manifest_dir,
+        Path::new("/tmp/out"),
+    );
+    assert_eq!(
+        invocation.args.last().unwrap(),
+        "/tmp/out/foo-afe61fbdb5de5f3b.o"
+    );
+    assert!(build.invocations().is_err());
+}
+
+#[test]
+fn test_compile_commands_json() {
+    let invocation = Invocation {
+        program: PathBuf::from("nasm"),
+        args: vec!["-felf64".into(), "C:\\src\\a \"b\".asm".into()],
+        input: PathBuf::from("C:\\src\\a \"b\".asm"),
+        output: PathBuf::from("out/a.o"),
+    };
+    assert_eq!(
+        compile_commands_json(&[invocation], Path::new("/crate")),
+        "[\n  {\n    \"directory\": \"/crate\",\n    \
+         \"arguments\": [\"nasm\", \"-felf64\", \"C:\\\\src\\\\a \\\"b\\\".asm\"],\n    \
+         \"file\": \"C:\\\\src\\\\a \\\"b\\\".asm\",\n    \
+         \"output\": \"out/a.o\"\n  }\n]\n"
+    );
+}
+
+#[test]
+fn test_preprocess_invocation() {
+    let mut build = Build::new();
+    build.out_dir("/tmp/out");
+    build.warnings_into_errors(false);
+    build.include("inc");
+    build.pic(false);
+    let invocation = build.preprocess_invocation(
+        Path::new("nasm"),
+        Path::new("src/foo.asm"),
+        &build.get_args("x86_64-unknown-linux-gnu"),
+        Path::new("/crate"),
+        Path::new("/tmp/out"),
+    );
+    assert_eq!(
+        invocation.output,
+        PathBuf::from("/tmp/out/foo-afe61fbdb5de5f3b.i")
+    );
+    assert_eq!(
+        invocation.args,
+        vec![
+            OsString::from("-felf64"),
+            OsString::from(format!("-I{}/inc/", env!("CARGO_MANIFEST_DIR"))),
+            include_arg(&build.generated_include_dir()),
+            OsString::from("-E"),
+            OsString::from("/crate/src/foo.asm"),
+            OsString::from("-o"),
+            OsString::from("/tmp/out/foo-afe61fbdb5de5f3b.i"),
+        ]
+    );
+}
+
+#[test]
+fn test_check_report() {
+    let report = CheckReport {
+        results: vec![
+            CheckResult {
+                target: "x86_64-unknown-linux-gnu".into(),
+                file: PathBuf::from("a.asm"),
+                success: true,
+                diagnostics: Vec::new(),
+            },
+            CheckResult {
+                target: "x86_64-pc-windows-msvc".into(),
+                file: PathBuf::from("a.asm"),
+                success: false,
+                diagnostics: vec![Diagnostic {
+                    file: Some(PathBuf::from("a.asm")),
+                    line: Some(4),
+                    severity: Severity::Error,
+                    message: "symbol `rel' not defined".into(),
+                }],
+            },
+        ],
+    };
+    assert!(!report.is_ok());
+    assert_eq!(report.failures().count(), 1);
+    assert_eq!(
+        report.to_string(),
+        "1 of 2 checks failed\n[x86_64-pc-windows-msvc] a.asm: FAILED\n  \
+         a.asm:4: error: symbol `rel' not defined"
+    );
+}
+
+#[test]
+fn test_warning_args() {
+    let mut build = Build::new();
+    build.out_dir("/tmp/out");
+    build.warning("orphan-labels", WarningLevel::Error);
+    build.warning("number-overflow", WarningLevel::Disable);
+    build.warnings_into_errors(true);
+    build.pic(false);
+    let macros = "-I/tmp/out/nasm-rs-include/";
+    assert_eq!(
+        build.get_args("x86_64-unknown-linux-gnu"),
+        &[
+            "-felf64",
+            "-w+error=orphan-labels",
+            "-w-number-overflow",
+            "-w+error",
+            macros
+        ]
+    );
+
+    build.assembler(Assembler::Yasm);
+    assert_eq!(
+        build.get_args("x86_64-unknown-linux-gnu"),
+        &[
+            "-f",
+            "elf64",
+            "-Worphan-labels",
+            "-Wno-number-overflow",
+            "-Werror",
+            macros
+        ]
+    );
+}
+
+#[test]
+fn test_opt_level() {
+    fn opt_flag(build: &Build, file: &str) -> OsString {
+        let args = build.get_args("x86_64-unknown-linux-gnu");
+        let invocation = build.invocation(
+            Path::new("nasm"),
+            Path::new(file),
+            &args,
+            Path::new("/crate"),
+            Path::new("/tmp/out"),
+        );
+        invocation.args[args.len()].clone()
+    }
+
+    let mut build = Build::new();
+    build.out_dir("/tmp/out");
+    build.opt_level(3);
+    build.file_opt_level("exact.asm", "0");
+    assert_eq!(opt_flag(&build, "fast.asm"), "-Ox");
+    assert_eq!(opt_flag(&build, "exact.asm"), "-O0");
+
+    build.opt_level_str("s");
+    assert_eq!(opt_flag(&build, "fast.asm"), "-Ox");
+    build.opt_level(1);
+    assert_eq!(opt_flag(&build, "fast.asm"), "-O1");
+}
+
+#[test]
+fn test_debug_info() {
+    assert_eq!("0".parse(), Ok(DebugInfo::None));
+    assert_eq!("false".parse(), Ok(DebugInfo::None));
+    assert_eq!("line-tables-only".parse(), Ok(DebugInfo::LineTablesOnly));
+    assert_eq!("limited".parse(), Ok(DebugInfo::Limited));
+    assert_eq!("2".parse(), Ok(DebugInfo::Full));
+    assert!("bogus".parse::<DebugInfo>().is_err());
+
+    let mut build = Build::new();
+    build.out_dir("/tmp/out");
+    build.warnings_into_errors(false);
+    build.debug_info(DebugInfo::Limited);
+    let macros = "-I/tmp/out/nasm-rs-include/";
+    assert_eq!(
+        build.get_args("x86_64-pc-windows-msvc"),
+        &["-fwin64", "-gcv8", macros]
+    );
+    build.pic(false);
+    assert_eq!(
+        build.get_args("x86_64-apple-darwin"),
+        &["-fmacho64", "-gdwarf", macros]
+    );
+    build.debug_info(DebugInfo::None);
+    assert_eq!(
+        build.get_args("x86_64-apple-darwin"),
+        &["-fmacho64", macros]
+    );
+}
+
+#[test]
+fn test_codegen_option_from_rustflags() {
+    assert_eq!(codegen_option_from_rustflags("", "relocation-model"), None);
+    assert_eq!(
+        codegen_option_from_rustflags(
+            "-Copt-level=3\x1f-Crelocation-model=static",
+            "relocation-model"
+        ),
+        Some("static")
+    );
+    assert_eq!(
+        codegen_option_from_rustflags(
+            "-C\x1frelocation-model=pic\x1f--codegen=relocation-model=dynamic-no-pic",
+            "relocation-model"
+        ),
+        Some("dynamic-no-pic")
+    );
+}
+
+#[test]
+fn test_include_arg() {
+    assert_eq!(include_arg(Path::new("/usr/include")), "-I/usr/include/");
+    assert_eq!(include_arg(Path::new("/usr/include/")), "-I/usr/include/");
+
+    let mut build = Build::new();
+    build.out_dir("/tmp/out");
+    build.warnings_into_errors(false);
+    build.include("/abs/inc");
+    build.pic(false);
+    assert_eq!(
+        build.get_args("x86_64-unknown-linux-gnu"),
+        &[
+            OsString::from("-felf64"),
+            OsString::from("-I/abs/inc/"),
+            include_arg(&build.generated_include_dir())
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_non_utf8_args() {
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    let dir = OsStr::from_bytes(b"/tmp/\xffinc");
+    let file = Path::new(OsStr::from_bytes(b"src/\xfe.asm"));
+    let mut build = Build::new();
+    build.out_dir("/tmp/out");
+    build.warnings_into_errors(false);
+    build.include(dir);
+    build.define_os("PATH", Some(OsStr::from_bytes(b"/tmp/\xfd")));
+    build.flag_os(OsStr::from_bytes(b"-MQ\xfc"));
+    build.pic(false);
+
+    let args = build.get_args("x86_64-unknown-linux-gnu");
+    assert_eq!(
+        args.iter()
+            .map(|a| a.clone().into_vec())
+            .collect::<Vec<_>>(),
+        vec![
+            b"-felf64".to_vec(),
+            b"-I/tmp/\xffinc/".to_vec(),
+            include_arg(&build.generated_include_dir()).into_vec(),
+            b"-DPATH=/tmp/\xfd".to_vec(),
+            b"-MQ\xfc".to_vec(),
+        ]
+    );
+
+    let invocation = build.invocation(
+        Path::new("nasm"),
+        file,
+        &args,
+        Path::new("/crate"),
+        Path::new("/tmp/out"),
+    );
+    assert_eq!(
+        invocation.input.as_os_str().as_bytes(),
+        b"/crate/src/\xfe.asm"
+    );
+    assert_eq!(
+        invocation.output.as_os_str().as_bytes(),
+        b"/tmp/out/\xfe-ca53cefa2ddf6575.o"
+    );
+    assert_eq!(
+        invocation.args.last().unwrap().as_bytes(),
+        b"/tmp/out/\xfe-ca53cefa2ddf6575.d"
+    );
+}
+
+#[test]
+fn test_response_file_contents() {
+    let args = [
+        OsString::from("-felf64"),
+        OsString::from("-I/dir with spaces/"),
+        OsString::from("-DMULTI=a\nb"),
+        OsString::from("-DPAD= "),
+        OsString::from("-o"),
+        OsString::from("/out/foo.o"),
+    ];
+    let (contents, rest) = response_file_contents(&args);
+    assert_eq!(
+        String::from_utf8(contents).unwrap(),
+        "-felf64\n-I/dir with spaces/\n-o\n/out/foo.o\n"
+    );
+    assert_eq!(rest, &["-DMULTI=a\nb", "-DPAD= "]);
+}
+
+#[test]
+fn test_response_file_command() {
+    let out_dir = env::temp_dir().join("nasm-rs-test-response-file");
+    std::fs::create_dir_all(&out_dir).unwrap();
+    let mut build = Build::new();
+    build.out_dir(&out_dir);
+    for i in 0..100 {
+        build.define(&format!("DEFINE_{}", i), Some("some reasonably long value"));
+    }
+    build.response_file_threshold(1024);
+    let args = build.get_args("x86_64-unknown-linux-gnu");
+    let invocation = build.invocation(
+        Path::new("nasm"),
+        Path::new("foo.asm"),
+        &args,
+        Path::new("/crate"),
+        &out_dir,
+    );
+
+    let cmd = build.assembler_command(&invocation).unwrap();
+    let cmd_args = cmd.get_args().collect::<Vec<_>>();
+    assert_eq!(cmd_args.len(), 2);
+    assert_eq!(cmd_args[0], "-@");
+    let contents = std::fs::read_to_string(cmd_args[1]).unwrap();
+    assert_eq!(contents.lines().count(), invocation.args.len());
+    assert!(contents.contains("-DDEFINE_99=some reasonably long value\n"));
+
+    build.response_file_threshold(usize::MAX);
+    let cmd = build.assembler_command(&invocation).unwrap();
+    assert_eq!(cmd.get_args().count(), invocation.args.len());
+    let _ = std::fs::remove_dir_all(&out_dir);
+}
+
+#[test]
+fn test_dir() {
+    let dir = env::temp_dir().join("nasm-rs-test-dir");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    for file in ["b.asm", "a.s", "c.inc", "sub/d.asm"] {
+        std::fs::write(dir.join(file), "").unwrap();
+    }
+
+    let mut build = Build::new();
+    build.dir(&dir, &["asm", "s"]);
+    assert_eq!(
+        build.get_files("x86_64-unknown-linux-gnu"),
+        vec![dir.join("a.s"), dir.join("b.asm")]
+    );
+
+    let mut build = Build::new();
+    build.files_glob(&format!("{}/**/*.asm", dir.display()));
+    assert_eq!(
+        build.get_files("x86_64-unknown-linux-gnu"),
+        vec![dir.join("b.asm"), dir.join("sub/d.asm")]
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_file_for_arch() {
+    let mut build = Build::new();
+    build.file("common.asm");
+    build.file_for_arch(Arch::X86, "x86/a.asm");
+    build.file_for_arch(Arch::X86_64, "x86_64/a.asm");
+    build.file_for_target(|t| t.os == "windows", "win/seh.asm");
+
+    assert_eq!(
+        build.get_files("i686-unknown-linux-gnu"),
+        vec![PathBuf::from("common.asm"), PathBuf::from("x86/a.asm")]
+    );
+    assert_eq!(
+        build.get_files("x86_64-pc-windows-msvc"),
+        vec![
+            PathBuf::from("common.asm"),
+            PathBuf::from("x86_64/a.asm"),
+            PathBuf::from("win/seh.asm"),
+        ]
+    );
+}
+
+#[test]
+fn test_pic() {
+    let mut build = Build::new();
+    build.out_dir("/tmp/out");
+    build.warnings_into_errors(false);
+    let macros = "-I/tmp/out/nasm-rs-include/";
+    assert_eq!(
+        build.get_args("x86_64-unknown-linux-gnu"),
+        &["-felf64", "-DPIC", macros]
+    );
+    assert_eq!(build.get_args("x86_64-unknown-none"), &["-felf64", macros]);
+
+    build.pic(true);
+    assert_eq!(
+        build.get_args("x86_64-unknown-linux-gnu"),
+        &["-felf64", "-DPIC", "--before", "default rel", macros]
+    );
+    assert_eq!(
+        build.get_args("i686-unknown-linux-gnu"),
+        &["-felf32", "-DPIC", macros]
+    );
+
+    build.assembler(Assembler::Yasm);
+    assert_eq!(
+        build.get_args("x86_64-unknown-linux-gnu"),
+        &["-f", "elf64", "-DPIC", macros]
+    );
+
+    build.pic(false);
+    assert_eq!(
+        build.get_args("x86_64-unknown-linux-gnu"),
+        &["-f", "elf64", macros]
+    );
+}
+
+#[test]
+fn test_pre_include() {
+    let out_dir = env::temp_dir().join("nasm-rs-test-pre-include");
+    let mut build = Build::new();
+    build.out_dir(&out_dir);
+    build.warnings_into_errors(false);
+    build.pic(false);
+    build.pre_include("/abs/config.inc");
+    build.prelude("%define ANSWER 42\n");
+    build.write_generated().unwrap();
+
+    let args = build.get_args("x86_64-unknown-linux-gnu");
+    assert_eq!(args[2], "-P/abs/config.inc");
+    let prelude = PathBuf::from(args[3].to_str().unwrap().strip_prefix("-P").unwrap());
+    assert!(prelude.starts_with(&out_dir));
+    assert_eq!(
+        std::fs::read_to_string(&prelude).unwrap(),
+        "%define ANSWER 42\n"
+    );
+
+    let _ = std::fs::remove_dir_all(&out_dir);
+}
+
+#[test]
+fn test_constants_include_dir() {
+    let out_dir = env::temp_dir().join("nasm-rs-test-constants");
+    let mut constants = Constants::new("answer.inc");
+    constants.equ("ANSWER", 42);
+    let mut build = Build::new();
+    build.out_dir(&out_dir);
+    build.warnings_into_errors(false);
+    build.pic(false);
+    build.constants(&constants);
+    build.write_generated().unwrap();
+
+    let include_dir = out_dir.join("nasm-rs-include");
+    assert_eq!(
+        build.get_args("x86_64-unknown-linux-gnu"),
+        &[OsString::from("-felf64"), include_arg(&include_dir)]
+    );
+    assert_eq!(
+        std::fs::read_to_string(include_dir.join("answer.inc")).unwrap(),
+        constants.contents()
+    );
+    assert_eq!(
+        std::fs::read_to_string(include_dir.join("nasm-rs/unwind.inc")).unwrap(),
+        UNWIND_INC
+    );
+
+    let _ = std::fs::remove_dir_all(&out_dir);
+}
+
+#[test]
+fn test_unwind_save_xmm_codes() {
+    let out_dir = env::temp_dir().join("nasm-rs-test-unwind");
+    std::fs::create_dir_all(&out_dir).unwrap();
+    let source = out_dir.join("save_xmm.asm");
+    std::fs::write(
+        &source,
+        "%include \"nasm-rs/unwind.inc\"\n\
+         UNWIND_PROC f\n\
+         UNWIND_ALLOC 40\n\
+         UNWIND_SAVE_XMM xmm6, 0\n\
+         UNWIND_SAVE_XMM xmm15, 16\n\
+         UNWIND_END_PROLOG\n\
+         ret\n\
+         UNWIND_ENDPROC\n",
+    )
+    .unwrap();
+    let mut build = Build::new();
+    build.out_dir(&out_dir);
+    build.target("x86_64-pc-windows-msvc");
+    build.warnings_into_errors(false);
+    if build.locate_nasm().is_err() {
+        return;
+    }
+
+    // Codes are listed in reverse: UWOP_SAVE_XMM128 (8) with xmm15 and xmm6
+    // in the high nibble, then UWOP_ALLOC_SMALL (2) of 40 bytes.
+    let output = build.preprocess(&source).unwrap();
+    let ops = output
+        .lines()
+        .filter(|line| line.trim_start().starts_with("db ..@"))
+        .filter_map(|line| line.rsplit_once(',').map(|(_, op)| op.trim()))
+        .collect::<Vec<_>>();
+    assert_eq!(ops, &["248", "104", "66"]);
+
+    let _ = std::fs::remove_dir_all(&out_dir);
 }
 
 #[test]
-fn test_parse_triple() {
-    let triple = "x86_64-unknown-linux-gnux32";
-    assert_eq!(parse_triple(triple), ("-felfx32", "-gdwarf"));
+fn test_rerun_lines_printed_once() {
+    let line = "cargo:rerun-if-env-changed=NASM_RS_TEST_PRINTED_ONCE".to_owned();
+    assert!(print_rerun_line(line.clone()));
+    assert!(!print_rerun_line(line));
+}
 
-    let triple = "x86_64-unknown-linux";
-    assert_eq!(parse_triple(triple), ("-felf64", "-gdwarf"));
+#[test]
+fn test_output_path() {
+    let dst = Path::new("/out");
+    let paths = [
+        output_path(dst, Path::new("src/a.asm"), "o"),
+        output_path(dst, Path::new("src/a.s"), "o"),
+        output_path(dst, Path::new("src/x86_64/a.asm"), "o"),
+    ];
+    for (i, path) in paths.iter().enumerate() {
+        assert_eq!(path.parent(), Some(dst));
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("a-") && name.ends_with(".o"), "{}", name);
+        assert!(!paths[i + 1..].contains(path));
+    }
+    assert_eq!(paths[0], output_path(dst, Path::new("src/a.asm"), "o"));
 }
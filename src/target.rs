@@ -0,0 +1,231 @@
+use std::env;
+use std::fmt;
+
+/// CPU architecture of a [`Target`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Arch {
+    /// 32-bit x86, e.g. `i686`
+    X86,
+    /// 64-bit x86
+    X86_64,
+    /// Any architecture NASM doesn't assemble for, e.g. `aarch64`
+    Other(String),
+}
+
+impl Arch {
+    fn parse(arch: &str) -> Self {
+        match arch {
+            "x86_64" => Arch::X86_64,
+            "x86" | "i386" | "i586" | "i686" => Arch::X86,
+            _ => Arch::Other(arch.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arch::X86 => f.write_str("x86"),
+            Arch::X86_64 => f.write_str("x86_64"),
+            Arch::Other(arch) => f.write_str(arch),
+        }
+    }
+}
+
+/// Operating systems which can appear right after the architecture in
+/// triples without a vendor, e.g. `x86_64-linux-android`.
+const VENDORLESS_OSES: &[&str] = &[
+    "linux",
+    "windows",
+    "darwin",
+    "ios",
+    "freebsd",
+    "netbsd",
+    "openbsd",
+    "dragonfly",
+    "solaris",
+    "illumos",
+    "fuchsia",
+    "haiku",
+    "hermit",
+    "redox",
+    "uefi",
+    "none",
+];
+
+/// Environments which may carry an ABI suffix, e.g. `gnux32` or `gnueabihf`.
+const ENVS: &[&str] = &["gnu", "musl", "uclibc", "msvc", "sgx", "ohos", "android"];
+
+/// The target being assembled for, e.g. `x86_64-unknown-linux-gnu`.
+///
+/// Parts missing from the triple are left empty.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Target {
+    pub arch: Arch,
+    pub vendor: String,
+    pub os: String,
+    /// The environment, e.g. `gnu` or `msvc`.
+    pub env: String,
+    /// The ABI, e.g. `x32` for `x86_64-unknown-linux-gnux32`.
+    pub abi: String,
+    /// Pointer width in bits.
+    pub pointer_width: u32,
+}
+
+impl Target {
+    /// Parses a triple of the form `ARCH[-VENDOR]-OS[-ENVIRONMENT]`.
+    pub fn parse(triple: &str) -> Self {
+        let mut parts = triple.split('-');
+        let arch = Arch::parse(parts.next().unwrap_or_default());
+        let mut parts = parts.map(str::to_owned).collect::<Vec<_>>();
+        if parts
+            .first()
+            .is_some_and(|part| VENDORLESS_OSES.contains(&part.as_str()))
+        {
+            parts.insert(0, String::new());
+        }
+        let mut parts = parts.into_iter();
+        let vendor = parts.next().unwrap_or_default();
+        let os = parts.next().unwrap_or_default();
+        let env_abi = parts.next().unwrap_or_default();
+
+        let (env, abi) = match ENVS.iter().find(|env| env_abi.starts_with(*env)) {
+            Some(env) => (env.to_string(), env_abi[env.len()..].to_owned()),
+            None => (env_abi, String::new()),
+        };
+        // Android triples put the environment where the OS usually is, and
+        // Cargo calls `darwin` `macos`; match `CARGO_CFG_TARGET_OS` for both.
+        let (os, env) = if os == "linux" && env == "android" {
+            ("android".to_owned(), String::new())
+        } else if os == "darwin" {
+            ("macos".to_owned(), env)
+        } else {
+            (os, env)
+        };
+
+        let pointer_width = match (&arch, abi.as_str()) {
+            (Arch::X86_64, "x32") => 32,
+            (Arch::X86_64, _) => 64,
+            (Arch::X86, _) => 32,
+            (Arch::Other(arch), _) if arch.contains("64") => 64,
+            (Arch::Other(_), _) => 32,
+        };
+
+        Target {
+            arch,
+            vendor,
+            os,
+            env,
+            abi,
+            pointer_width,
+        }
+    }
+
+    /// The target Cargo is building for.
+    ///
+    /// Parsed from `TARGET`, with each part replaced by the matching
+    /// `CARGO_CFG_TARGET_*` variable if it is set. Those come from the target
+    /// spec, so they are also correct for custom targets whose names don't
+    /// follow the usual triple format. Returns `None` outside of a build script.
+    pub fn from_env() -> Option<Self> {
+        let triple = env::var("TARGET").ok()?;
+        Some(Self::with_cfg(&triple, |key| env::var(key).ok()))
+    }
+
+    /// Describes `triple`, using the `CARGO_CFG_TARGET_*` variables if it is
+    /// the target Cargo is building for; they don't describe any other target.
+    pub(crate) fn resolve(triple: &str) -> Self {
+        if env::var("TARGET").is_ok_and(|t| t == triple) {
+            Self::with_cfg(triple, |key| env::var(key).ok())
+        } else {
+            Self::parse(triple)
+        }
+    }
+
+    pub(crate) fn with_cfg<F: Fn(&str) -> Option<String>>(triple: &str, cfg: F) -> Self {
+        let mut target = Self::parse(triple);
+        if let Some(arch) = cfg("CARGO_CFG_TARGET_ARCH") {
+            target.arch = Arch::parse(&arch);
+        }
+        if let Some(vendor) = cfg("CARGO_CFG_TARGET_VENDOR") {
+            target.vendor = vendor;
+        }
+        if let Some(os) = cfg("CARGO_CFG_TARGET_OS") {
+            target.os = os;
+        }
+        if let Some(env) = cfg("CARGO_CFG_TARGET_ENV") {
+            target.env = env;
+        }
+        if let Some(abi) = cfg("CARGO_CFG_TARGET_ABI") {
+            target.abi = abi;
+        }
+        if let Some(width) = cfg("CARGO_CFG_TARGET_POINTER_WIDTH").and_then(|w| w.parse().ok()) {
+            target.pointer_width = width;
+        }
+        target
+    }
+}
+
+#[test]
+fn test_parse_target() {
+    let target = Target::parse("i686-pc-windows-msvc");
+    assert_eq!(target.arch, Arch::X86);
+    assert_eq!(target.vendor, "pc");
+    assert_eq!(target.os, "windows");
+    assert_eq!(target.env, "msvc");
+    assert_eq!(target.pointer_width, 32);
+
+    let target = Target::parse("aarch64-apple-darwin");
+    assert_eq!(target.arch, Arch::Other("aarch64".into()));
+    assert_eq!(target.os, "macos");
+    assert_eq!(target.env, "");
+    assert_eq!(target.pointer_width, 64);
+
+    let target = Target::parse("x86_64-unknown-linux-gnux32");
+    assert_eq!(target.env, "gnu");
+    assert_eq!(target.abi, "x32");
+    assert_eq!(target.pointer_width, 32);
+}
+
+#[test]
+fn test_parse_vendorless_target() {
+    let target = Target::parse("x86_64-linux-android");
+    assert_eq!(target.arch, Arch::X86_64);
+    assert_eq!(target.vendor, "");
+    assert_eq!(target.os, "android");
+    assert_eq!(target.env, "");
+
+    let target = Target::parse("i686-linux-gnu");
+    assert_eq!(target.arch, Arch::X86);
+    assert_eq!(target.os, "linux");
+    assert_eq!(target.env, "gnu");
+}
+
+#[test]
+fn test_target_with_cfg() {
+    let target = Target::with_cfg("x86_64-unknown-linux-gnux32", |key| match key {
+        "CARGO_CFG_TARGET_ENV" => Some("gnu".into()),
+        "CARGO_CFG_TARGET_ABI" => Some("x32".into()),
+        "CARGO_CFG_TARGET_POINTER_WIDTH" => Some("32".into()),
+        _ => None,
+    });
+    assert_eq!(target.os, "linux");
+    assert_eq!(target.abi, "x32");
+    assert_eq!(target.pointer_width, 32);
+}
+
+#[test]
+fn test_custom_target_with_cfg() {
+    let target = Target::with_cfg("x86_64-ourkernel", |key| match key {
+        "CARGO_CFG_TARGET_ARCH" => Some("x86_64".into()),
+        "CARGO_CFG_TARGET_VENDOR" => Some("unknown".into()),
+        "CARGO_CFG_TARGET_OS" => Some("none".into()),
+        "CARGO_CFG_TARGET_ENV" => Some("".into()),
+        "CARGO_CFG_TARGET_POINTER_WIDTH" => Some("64".into()),
+        _ => None,
+    });
+    assert_eq!(target.arch, Arch::X86_64);
+    assert_eq!(target.vendor, "unknown");
+    assert_eq!(target.os, "none");
+    assert_eq!(target.pointer_width, 64);
+}